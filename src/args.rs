@@ -0,0 +1,633 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use chrono::{DateTime, FixedOffset, NaiveDate};
+use structopt::StructOpt;
+
+use crate::settings::{Credentials, Server};
+
+/// Command line arguments
+#[derive(Debug, StructOpt)]
+#[structopt(name = "postkast", about = "Modern desktop email client")]
+pub struct Args {
+    /// Configuration file to use instead of the default one, in TOML, YAML or JSON format
+    #[structopt(long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
+    /// Name of the configured server to use instead of all of them, ignoring case
+    #[structopt(long)]
+    pub server: Option<String>,
+
+    /// Log in as `username:password` instead of with the configured credentials
+    #[structopt(long, value_name = "username:password")]
+    pub login: Option<Credentials>,
+
+    /// Include the servers disabled in the configuration
+    #[structopt(long)]
+    pub all: bool,
+
+    /// Mailbox folder to list messages from instead of the default folder of the server.
+    /// Listing accepts several comma separated or repeated folders, listed one after another with a single login
+    #[structopt(long = "folder", value_name = "folder", use_delimiter = true, number_of_values = 1)]
+    pub folders: Vec<String>,
+
+    /// Maximum number of messages to list, newest first
+    #[structopt(long)]
+    pub limit: Option<u32>,
+
+    /// Number of newest messages to skip
+    #[structopt(long, default_value = "0")]
+    pub offset: u32,
+
+    /// Only list messages received on or after this date, given as YYYY-MM-DD
+    #[structopt(long, parse(try_from_str = parse_date))]
+    pub since: Option<NaiveDate>,
+
+    /// Only list messages received before this date, given as YYYY-MM-DD
+    #[structopt(long, parse(try_from_str = parse_date))]
+    pub before: Option<NaiveDate>,
+
+    /// Only list messages newer than the newest one listed by the previous run with this flag
+    #[structopt(long)]
+    pub new_only: bool,
+
+    /// Only list messages added or changed, e.g. read or flagged, since the previous run with this flag.
+    /// Servers without the CONDSTORE extension only list the new messages, as with --new-only.
+    #[structopt(long, conflicts_with = "new-only")]
+    pub changed_since: bool,
+
+    /// Fetch and print the text body of the messages
+    #[structopt(long)]
+    pub body: bool,
+
+    /// Fail on message envelopes that are not valid UTF-8, instead of showing them as well as possible
+    #[structopt(long)]
+    pub strict_encoding: bool,
+
+    /// Comma separated header fields to fetch instead of the whole envelope:
+    /// from, to, cc, bcc, date or subject, the received date and size are always fetched
+    #[structopt(long, use_delimiter = true)]
+    pub fields: Vec<Field>,
+
+    /// Comma separated envelope fields to print in the text format:
+    /// from, to, cc, bcc, date, received, size or subject
+    #[structopt(long, default_value = "from,date,subject", use_delimiter = true)]
+    pub show: Vec<Field>,
+
+    /// Most addresses to print of each address list in the text format, followed by how many more there are
+    #[structopt(long, default_value = "3")]
+    pub max_addresses: usize,
+
+    /// Print every address of the address lists in the text format
+    #[structopt(long)]
+    pub full: bool,
+
+    /// Mark the listed messages as read, which listing does not do by itself
+    #[structopt(long)]
+    pub mark_read: bool,
+
+    /// Save the attachments of the listed messages into this directory
+    #[structopt(long, parse(from_os_str))]
+    pub save_attachments: Option<PathBuf>,
+
+    /// Order the listed messages by this key instead of the server order: date, arrival, size, subject or from.
+    /// Servers supporting the SORT extension sort the messages themselves
+    #[structopt(long)]
+    pub sort: Option<SortKey>,
+
+    /// List the messages in reverse order, newest first when sorting by date
+    #[structopt(long)]
+    pub reverse: bool,
+
+    /// Group the listed messages into conversation threads, with replies indented below
+    /// the message they reply to. Servers supporting the THREAD extension thread them themselves
+    #[structopt(long)]
+    pub thread: bool,
+
+    /// Output format: text, table, json or ndjson
+    #[structopt(long, default_value = "text")]
+    pub format: Format,
+
+    /// Colorize unseen messages, senders and dates of the text format: auto, always or never.
+    /// Auto colors only the output to a terminal, unless NO_COLOR is set
+    #[structopt(long, default_value = "auto")]
+    pub color: Color,
+
+    /// Comma separated columns of the table format: uid, date, received, size, from, to, subject or flags
+    #[structopt(long, default_value = "date,from,subject", use_delimiter = true)]
+    pub columns: Vec<Column>,
+
+    /// Print each message of the text format as one line of this template instead, with the placeholders
+    /// {uid}, {date}, {received}, {size}, {from}, {to}, {subject} and {flags} replaced by the values of the
+    /// table columns, e.g. "{date} {from}: {subject}". Write {{ and }} for literal braces,
+    /// other placeholders are rejected
+    #[structopt(long)]
+    pub template: Option<Template>,
+
+    /// Number of servers to connect to concurrently, defaults to all of them
+    #[structopt(long)]
+    pub jobs: Option<usize>,
+
+    /// Number of messages to fetch at a time, bounding memory use on large folders
+    #[structopt(long, default_value = "500")]
+    pub batch_size: usize,
+
+    /// Number of times to retry a connection failing with a transient error
+    #[structopt(long, default_value = "3")]
+    pub retries: u32,
+
+    /// Allow the flag, move, copy and delete commands and --mark-read to change messages on the server,
+    /// which are only read otherwise
+    #[structopt(long)]
+    pub write: bool,
+
+    /// Show what the flag, move, copy and delete commands and --mark-read would change,
+    /// without changing anything on the server
+    #[structopt(long)]
+    pub dry_run: bool,
+
+    /// Log more diagnostics to stderr, -v for progress and -vv for protocol details.
+    /// The RUST_LOG environment variable takes precedence
+    #[structopt(short, long, parse(from_occurrences))]
+    pub verbose: u8,
+
+    /// Print only the messages and other requested data, without listing headers, summaries
+    /// and progress, and log only errors
+    #[structopt(short, long, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+// Available subcommands, listing messages in a folder when none is given.
+// Not a doc comment, as structopt would use it as the description of the whole program.
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Write a sample configuration file to edit, to the default location or the `--config` path
+    Init {
+        /// Overwrite an existing configuration file
+        #[structopt(long)]
+        force: bool,
+    },
+    /// Print the JSON Schema of the configuration file, for validating it in editors
+    Schema,
+    /// List the newest messages in the folder
+    List,
+    /// List all available mailboxes on the server
+    Folders,
+    /// Show the message counts of the folder without fetching any messages
+    Status,
+    /// Show the storage quota usage of the folder
+    Quota,
+    /// List the capabilities the server advertises, such as IDLE, MOVE, SORT or QUOTA
+    Capabilities,
+    /// List the messages of the folder matching IMAP search criteria,
+    /// e.g. `FROM alice@example.com` or `SINCE 1-Jan-2024 UNSEEN`
+    Search {
+        /// Search with a Gmail search box query instead, e.g. `has:attachment larger:5M`,
+        /// on Gmail servers with the X-GM-EXT-1 capability
+        #[structopt(long)]
+        gmail: bool,
+        /// Search keys and their arguments, or the words of the Gmail query
+        #[structopt(required = true)]
+        criteria: Vec<String>,
+    },
+    /// Mark a message as read, unread, flagged or unflagged
+    Flag {
+        /// Folder containing the message, flags are kept per folder
+        folder: String,
+        /// UID of the message, as shown in the message listing
+        uid: u32,
+        /// Mark the message as read
+        #[structopt(long, conflicts_with = "unseen")]
+        seen: bool,
+        /// Mark the message as unread
+        #[structopt(long)]
+        unseen: bool,
+        /// Flag the message for attention
+        #[structopt(long, conflicts_with = "unflagged")]
+        flagged: bool,
+        /// Remove the attention flag of the message
+        #[structopt(long)]
+        unflagged: bool,
+    },
+    /// Move messages to another folder
+    Move {
+        /// Folder containing the messages
+        source: String,
+        /// UID or UID set of the messages, e.g. `42`, `40:45` or `1,3,5`
+        uids: String,
+        /// Folder to move the messages to
+        destination: String,
+    },
+    /// Copy messages to another folder
+    Copy {
+        /// Folder containing the messages
+        source: String,
+        /// UID or UID set of the messages, e.g. `42`, `40:45` or `1,3,5`
+        uids: String,
+        /// Folder to copy the messages to
+        destination: String,
+    },
+    /// Flag messages as deleted, optionally expunging them for good
+    Delete {
+        /// Folder containing the messages
+        folder: String,
+        /// UID or UID set of the messages, e.g. `42`, `40:45` or `1,3,5`
+        uids: String,
+        /// Permanently remove the messages instead of only flagging them
+        #[structopt(long)]
+        expunge: bool,
+    },
+    /// Watch the folder and print new messages as they arrive, until interrupted with Ctrl-C
+    Watch {
+        /// Seconds between checks on servers without IDLE support, and between
+        /// refreshes of the IDLE command on servers with it
+        #[structopt(long, default_value = "60")]
+        interval: u64,
+    },
+    /// Save every message of the folder locally, fetching them in batches
+    Export {
+        /// Maildir directory to save the messages into, created if missing
+        #[structopt(long, parse(from_os_str), required_unless = "mbox", conflicts_with = "mbox")]
+        maildir: Option<PathBuf>,
+        /// Mbox file to append the messages to, created if missing
+        #[structopt(long, parse(from_os_str))]
+        mbox: Option<PathBuf>,
+    },
+    /// Store the password of a server in the system keyring
+    SetPassword {
+        /// Name of the server in the configuration
+        server: String,
+    },
+    /// Send a plain text message read from standard input
+    Send {
+        /// Name of the server in the configuration to send the message with
+        server: String,
+        /// Recipient address
+        to: String,
+        /// Message subject
+        #[structopt(long, default_value = "")]
+        subject: String,
+    },
+    /// Reply to a message of the folder, writing the reply in $EDITOR
+    Reply {
+        /// Name of the server in the configuration with the message, and to send the reply with
+        server: String,
+        /// UID of the message, as shown in the message listing
+        uid: u32,
+    },
+    /// Print a message of the folder as is, in its raw RFC 822 form
+    Cat {
+        /// Name of the server in the configuration with the message
+        server: String,
+        /// UID of the message, as shown in the message listing
+        uid: u32,
+        /// Print only this MIME part, decoded, by its number as shown by the structure command, e.g. `2.1`
+        #[structopt(long, value_name = "section")]
+        part: Option<String>,
+    },
+    /// Print the MIME part tree of a message with the part numbers, types, sizes and file names,
+    /// without fetching the parts themselves
+    Structure {
+        /// Name of the server in the configuration with the message
+        server: String,
+        /// UID of the message, as shown in the message listing
+        uid: u32,
+    },
+    /// Upload a raw RFC 822 message into a folder, e.g. to import mail or save a draft
+    Append {
+        /// Folder to upload the message into
+        folder: String,
+        /// File with the message, read from standard input unless given
+        #[structopt(parse(from_os_str))]
+        file: Option<PathBuf>,
+        /// Flags to set on the message, e.g. `\Seen` or `\Draft`
+        #[structopt(long = "flag", value_name = "flag")]
+        flags: Vec<String>,
+        /// Time the message was received to keep on the server, as `2024-01-31T12:00:00+02:00`, now unless given
+        #[structopt(long, parse(try_from_str = parse_date_time))]
+        date: Option<DateTime<FixedOffset>>,
+    },
+    /// List the messages of the folder and read them one at a time by their number, until Ctrl-D
+    Browse {
+        /// Name of the server in the configuration to browse
+        server: String,
+    },
+}
+
+impl Args {
+    /// The folder commands working on a single folder operate on, the default folder of the `server` unless given
+    pub fn folder<'a>(&'a self, server: &'a Server) -> &'a str {
+        self.folders.first().map_or(server.default_folder(), String::as_str)
+    }
+
+    /// The folders to list, the default folder of the `server` unless given
+    pub fn folders<'a>(&'a self, server: &'a Server) -> Vec<&'a str> {
+        if self.folders.is_empty() {
+            vec![server.default_folder()]
+        } else {
+            self.folders.iter().map(String::as_str).collect()
+        }
+    }
+
+    /// Whether the listed messages are filtered by date
+    pub fn has_date_filter(&self) -> bool {
+        self.since.is_some() || self.before.is_some()
+    }
+}
+
+fn parse_date(date: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|err| format!("Invalid date '{:}', expected YYYY-MM-DD: {:}", date, err))
+}
+
+fn parse_date_time(date: &str) -> Result<DateTime<FixedOffset>, String> {
+    DateTime::parse_from_rfc3339(date)
+        .map_err(|err| format!("Invalid date '{:}', expected YYYY-MM-DDTHH:MM:SS+HH:MM: {:}", date, err))
+}
+
+impl Command {
+    /// Name of the single server this command operates on, if it names one
+    pub fn server_name(&self) -> Option<&str> {
+        match self {
+            Command::SetPassword { server } | Command::Send { server, .. } | Command::Reply { server, .. }
+            | Command::Cat { server, .. } | Command::Structure { server, .. } | Command::Browse { server } => Some(server.as_str()),
+            _ => None,
+        }
+    }
+}
+
+/// When to colorize the text output
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Color {
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Color::Auto),
+            "always" => Ok(Color::Always),
+            "never" => Ok(Color::Never),
+            other => Err(format!("Unknown color choice '{:}', expected 'auto', 'always' or 'never'", other)),
+        }
+    }
+}
+
+/// Output format of the message listing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    /// Aligned columns, one message per line
+    Table,
+    Json,
+    /// One JSON object per line, printed as the messages are fetched
+    Ndjson,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "table" => Ok(Format::Table),
+            "json" => Ok(Format::Json),
+            "ndjson" => Ok(Format::Ndjson),
+            other => Err(format!("Unknown output format '{:}', expected 'text', 'table', 'json' or 'ndjson'", other)),
+        }
+    }
+}
+
+/// Message header field to fetch or print
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    From,
+    To,
+    Cc,
+    Bcc,
+    Date,
+    /// Date the server received the message on, its INTERNALDATE
+    Received,
+    /// Size of the message, its RFC822.SIZE
+    Size,
+    Subject,
+}
+
+impl Field {
+    /// Name of the message header, none for the fields the server keeps apart from the header
+    pub fn header_name(&self) -> Option<&'static str> {
+        match self {
+            Field::From => Some("From"),
+            Field::To => Some("To"),
+            Field::Cc => Some("Cc"),
+            Field::Bcc => Some("Bcc"),
+            Field::Date => Some("Date"),
+            Field::Received | Field::Size => None,
+            Field::Subject => Some("Subject"),
+        }
+    }
+}
+
+impl FromStr for Field {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "from" => Ok(Field::From),
+            "to" => Ok(Field::To),
+            "cc" => Ok(Field::Cc),
+            "bcc" => Ok(Field::Bcc),
+            "date" => Ok(Field::Date),
+            "received" => Ok(Field::Received),
+            "size" => Ok(Field::Size),
+            "subject" => Ok(Field::Subject),
+            _ => Err(format!("Unknown header field '{:}', expected 'from', 'to', 'cc', 'bcc', 'date', 'received', 'size' or 'subject'", s)),
+        }
+    }
+}
+
+/// Column of the table output format
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Column {
+    Uid,
+    Date,
+    /// Time the server received the message
+    Received,
+    Size,
+    From,
+    To,
+    Subject,
+    Flags,
+}
+
+impl Column {
+    /// Heading of the column
+    pub fn title(&self) -> &'static str {
+        match self {
+            Column::Uid => "UID",
+            Column::Date => "Date",
+            Column::Received => "Received",
+            Column::Size => "Size",
+            Column::From => "From",
+            Column::To => "To",
+            Column::Subject => "Subject",
+            Column::Flags => "Flags",
+        }
+    }
+}
+
+/// Line printed for each message, with placeholders for the values of table columns
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template(Vec<TemplatePart>);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplatePart {
+    Text(String),
+    Column(Column),
+}
+
+impl Template {
+    pub fn parts(&self) -> &[TemplatePart] {
+        &self.0
+    }
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                },
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                },
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("Unclosed placeholder '{{{:}' in template", name)),
+                        }
+                    }
+                    let column = name.parse::<Column>()
+                        .map_err(|_| format!("Unknown placeholder '{{{:}}}' in template, expected {{uid}}, {{date}}, \
+                            {{received}}, {{size}}, {{from}}, {{to}}, {{subject}} or {{flags}}, or {{{{ for a literal brace", name))?;
+                    if !text.is_empty() {
+                        parts.push(TemplatePart::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(TemplatePart::Column(column));
+                },
+                '}' => return Err("Unmatched '}' in template, write }} for a literal brace".to_string()),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(TemplatePart::Text(text));
+        }
+        Ok(Template(parts))
+    }
+}
+
+impl FromStr for Column {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "uid" => Ok(Column::Uid),
+            "date" => Ok(Column::Date),
+            "received" => Ok(Column::Received),
+            "size" => Ok(Column::Size),
+            "from" => Ok(Column::From),
+            "to" => Ok(Column::To),
+            "subject" => Ok(Column::Subject),
+            "flags" => Ok(Column::Flags),
+            _ => Err(format!("Unknown column '{:}', expected 'uid', 'date', 'received', 'size', 'from', 'to', 'subject' or 'flags'", s)),
+        }
+    }
+}
+
+/// Key to sort the listed messages by
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortKey {
+    Date,
+    /// Time the server received the message
+    Arrival,
+    Size,
+    Subject,
+    From,
+}
+
+impl SortKey {
+    /// Name of the sort key in the IMAP SORT command
+    pub fn imap_name(&self) -> &'static str {
+        match self {
+            SortKey::Date => "DATE",
+            SortKey::Arrival => "ARRIVAL",
+            SortKey::Size => "SIZE",
+            SortKey::Subject => "SUBJECT",
+            SortKey::From => "FROM",
+        }
+    }
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "date" => Ok(SortKey::Date),
+            "arrival" => Ok(SortKey::Arrival),
+            "size" => Ok(SortKey::Size),
+            "subject" => Ok(SortKey::Subject),
+            "from" => Ok(SortKey::From),
+            other => Err(format!("Unknown sort key '{:}', expected 'date', 'arrival', 'size', 'subject' or 'from'", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_format_shows_the_sender_date_and_subject_by_default() {
+        assert_eq!(Args::from_iter(&["postkast"]).show, [Field::From, Field::Date, Field::Subject]);
+    }
+
+    #[test]
+    fn template_placeholders_and_escaped_braces_are_parsed() {
+        let template: Template = "{{{uid}}} {from}: {subject}}}".parse().unwrap();
+        assert_eq!(template.parts(), [
+            TemplatePart::Text("{".to_string()),
+            TemplatePart::Column(Column::Uid),
+            TemplatePart::Text("} ".to_string()),
+            TemplatePart::Column(Column::From),
+            TemplatePart::Text(": ".to_string()),
+            TemplatePart::Column(Column::Subject),
+            TemplatePart::Text("}".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn invalid_templates_are_rejected() {
+        assert!("{sender}".parse::<Template>().unwrap_err().starts_with("Unknown placeholder '{sender}'"));
+        assert_eq!("{subject".parse::<Template>(), Err("Unclosed placeholder '{subject' in template".to_string()));
+        assert_eq!("subject}".parse::<Template>(), Err("Unmatched '}' in template, write }} for a literal brace".to_string()));
+    }
+}
@@ -0,0 +1,63 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::mime;
+
+/// Save all attachments of a raw RFC 822 message into `dir`, creating it if needed.
+///
+/// Returns the paths of the written files.
+pub fn save_attachments(dir: &Path, message: &[u8]) -> io::Result<Vec<PathBuf>> {
+    let attachments = mime::attachments(message);
+    if attachments.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    fs::create_dir_all(dir)?;
+    let mut saved = Vec::with_capacity(attachments.len());
+    for attachment in attachments {
+        let path = unique_path(dir, &sanitize_filename(&attachment.filename));
+        fs::write(&path, &attachment.content)?;
+        saved.push(path);
+    }
+    Ok(saved)
+}
+
+/// Reduce a file name suggested by the message to a plain file name,
+/// so that it can not escape the target directory.
+fn sanitize_filename(filename: &str) -> String {
+    let filename = filename.rsplit(['/', '\\']).next().unwrap_or("");
+    let filename: String = filename.chars()
+        .filter(|c| !c.is_control())
+        .collect();
+    let filename = filename.trim();
+
+    if filename.is_empty() || filename.chars().all(|c| c == '.') {
+        "attachment".to_string()
+    } else {
+        filename.to_string()
+    }
+}
+
+/// Path of `filename` in `dir`, with a numeric suffix appended if that file already exists
+fn unique_path(dir: &Path, filename: &str) -> PathBuf {
+    let path = dir.join(filename);
+    if !path.exists() {
+        return path;
+    }
+
+    let stem = Path::new(filename).file_stem().and_then(OsStr::to_str).unwrap_or(filename);
+    let extension = Path::new(filename).extension().and_then(OsStr::to_str);
+    let mut suffix = 1;
+    loop {
+        let candidate = match extension {
+            Some(extension) => dir.join(format!("{:}-{:}.{:}", stem, suffix, extension)),
+            None => dir.join(format!("{:}-{:}", stem, suffix)),
+        };
+        if !candidate.exists() {
+            return candidate;
+        }
+        suffix += 1;
+    }
+}
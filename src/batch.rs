@@ -0,0 +1,27 @@
+use imap::types::Uid;
+
+/// Compact set of the given sorted UIDs or sequence numbers, with consecutive runs collapsed into ranges
+pub fn uid_set(uids: &[Uid]) -> String {
+    let mut ranges: Vec<String> = Vec::new();
+    let mut iter = uids.iter().copied().peekable();
+    while let Some(first) = iter.next() {
+        let mut last = first;
+        while iter.peek() == Some(&(last + 1)) {
+            last += 1;
+            iter.next();
+        }
+        if first == last {
+            ranges.push(first.to_string());
+        } else {
+            ranges.push(format!("{:}:{:}", first, last));
+        }
+    }
+    ranges.join(",")
+}
+
+/// Consecutive batches of at most `size` of the sorted UIDs or sequence numbers `uids`,
+/// each with its compact set for commands like `UID FETCH`, so that large ranges
+/// are fetched and processed one batch at a time
+pub fn batches(uids: &[Uid], size: usize) -> impl Iterator<Item = (&[Uid], String)> {
+    uids.chunks(size.max(1)).map(|batch| (batch, uid_set(batch)))
+}
@@ -0,0 +1,193 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, FixedOffset, Utc};
+use mailparse::{MailAddr, MailHeaderMap};
+
+/// Sender of the `From ` line of messages without a sender address
+const UNKNOWN_SENDER: &str = "MAILER-DAEMON";
+
+/// Local folder in Maildir format (https://cr.yp.to/proto/maildir.html)
+pub struct Maildir {
+    path: PathBuf,
+    /// Hostname part of the file names, with `/` and `:` escaped
+    hostname: String,
+    /// Number of messages added, keeping the file names unique within the process
+    count: u32,
+}
+
+impl Maildir {
+    /// Open the Maildir at `path`, creating it and its `tmp`, `new` and `cur` directories as needed
+    pub fn create(path: &Path) -> io::Result<Self> {
+        for dir in &["tmp", "new", "cur"] {
+            fs::create_dir_all(path.join(dir))?;
+        }
+        let hostname = env::var("HOSTNAME").ok()
+            .or_else(|| fs::read_to_string("/etc/hostname").ok())
+            .map(|hostname| hostname.trim().to_string())
+            .filter(|hostname| !hostname.is_empty())
+            .unwrap_or_else(|| "localhost".to_string());
+        Ok(Maildir {
+            path: path.to_path_buf(),
+            hostname: hostname.replace('/', "\\057").replace(':', "\\072"),
+            count: 0,
+        })
+    }
+
+    /// Add the raw `message` with its IMAP `flags`. The message is written to `tmp` and then
+    /// moved to `new`, or to `cur` when it has any flags to keep in the file name.
+    pub fn add(&mut self, message: &[u8], flags: &[String]) -> io::Result<()> {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+        self.count += 1;
+        let name = format!("{:}.P{:}Q{:}.{:}", time, process::id(), self.count, self.hostname);
+
+        let tmp = self.path.join("tmp").join(&name);
+        fs::write(&tmp, unix_line_endings(message))?;
+        let info = maildir_flags(flags);
+        let destination = if info.is_empty() {
+            self.path.join("new").join(name)
+        } else {
+            self.path.join("cur").join(format!("{:}:2,{:}", name, info))
+        };
+        fs::rename(tmp, destination)
+    }
+}
+
+/// Local mailbox file in the mboxrd format, where lines starting with `From ` after any number of `>`
+/// characters get one more `>`, so that they can be told apart from the `From ` lines starting messages
+pub struct Mbox {
+    file: BufWriter<File>,
+}
+
+impl Mbox {
+    /// Open the mbox file at `path` for appending messages, creating it if missing
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Mbox { file: BufWriter::new(file) })
+    }
+
+    /// Append the raw `message` received by the server at `received`, or now if unknown
+    pub fn add(&mut self, message: &[u8], received: Option<DateTime<FixedOffset>>) -> io::Result<()> {
+        let received = received.map_or_else(Utc::now, |received| received.with_timezone(&Utc));
+        writeln!(self.file, "From {:} {:}", sender(message), received.format("%a %b %e %H:%M:%S %Y"))?;
+
+        let message = unix_line_endings(message);
+        for line in message.split_inclusive(|&byte| byte == b'\n') {
+            if is_from_line(line) {
+                self.file.write_all(b">")?;
+            }
+            self.file.write_all(line)?;
+        }
+        // messages end with an empty line, also when the last line is missing its line ending
+        if !message.ends_with(b"\n") {
+            self.file.write_all(b"\n")?;
+        }
+        self.file.write_all(b"\n")
+    }
+
+    /// Write out the buffered messages
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Whether the `line` starts with `From `, after any number of `>` characters
+fn is_from_line(line: &[u8]) -> bool {
+    let start = line.iter().position(|&byte| byte != b'>').unwrap_or(line.len());
+    line[start..].starts_with(b"From ")
+}
+
+/// Envelope sender address of the `message` for its `From ` line, from its Return-Path or From header
+fn sender(message: &[u8]) -> String {
+    let headers = match mailparse::parse_headers(message) {
+        Ok((headers, _)) => headers,
+        Err(_) => return UNKNOWN_SENDER.to_string(),
+    };
+    headers.get_first_value("Return-Path").into_iter()
+        .chain(headers.get_first_value("From"))
+        .filter_map(|value| mailparse::addrparse(&value).ok())
+        .find_map(|addresses| addresses.iter().find_map(|address| match address {
+            MailAddr::Single(single) => Some(single.addr.clone()),
+            MailAddr::Group(group) => group.addrs.first().map(|single| single.addr.clone()),
+        }))
+        .filter(|address| !address.is_empty() && !address.contains(char::is_whitespace))
+        .unwrap_or_else(|| UNKNOWN_SENDER.to_string())
+}
+
+/// Maildir flag letters of the IMAP `flags`, in the required alphabetical order
+fn maildir_flags(flags: &[String]) -> String {
+    let has = |flag: &str| flags.iter().any(|set| set.eq_ignore_ascii_case(flag));
+    [("\\Draft", 'D'), ("\\Flagged", 'F'), ("\\Answered", 'R'), ("\\Seen", 'S'), ("\\Deleted", 'T')].iter()
+        .filter(|(flag, _)| has(flag))
+        .map(|(_, letter)| *letter)
+        .collect()
+}
+
+/// The `message` with the CRLF line endings of the IMAP protocol turned into the LF of local files
+fn unix_line_endings(message: &[u8]) -> Vec<u8> {
+    let mut converted = Vec::with_capacity(message.len());
+    let mut bytes = message.iter().peekable();
+    while let Some(&byte) = bytes.next() {
+        if byte != b'\r' || bytes.peek() != Some(&&b'\n') {
+            converted.push(byte);
+        }
+    }
+    converted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(flags: &[&str]) -> Vec<String> {
+        flags.iter().map(|flag| flag.to_string()).collect()
+    }
+
+    #[test]
+    fn maildir_flags_are_sorted_letters() {
+        assert_eq!(maildir_flags(&flags(&["\\Seen", "\\Answered", "\\Draft", "\\Flagged", "\\Deleted"])), "DFRST");
+        assert_eq!(maildir_flags(&flags(&["\\seen", "$Forwarded", "\\Recent"])), "S");
+        assert_eq!(maildir_flags(&[]), "");
+    }
+
+    #[test]
+    fn from_lines_are_recognized_after_any_quoting() {
+        assert!(is_from_line(b"From alice@example.com Mon Feb  7 21:52:25 1994\n"));
+        assert!(is_from_line(b">From here\n"));
+        assert!(is_from_line(b">>From here\n"));
+        assert!(!is_from_line(b"From: alice@example.com\n"));
+        assert!(!is_from_line(b"> From here\n"));
+        assert!(!is_from_line(b""));
+    }
+
+    #[test]
+    fn crlf_line_endings_become_lf() {
+        assert_eq!(unix_line_endings(b"a\r\nb\rc\n\r\n"), b"a\nb\rc\n\n");
+    }
+
+    #[test]
+    fn sender_falls_back_from_return_path_to_from() {
+        assert_eq!(sender(b"Return-Path: <bounce@example.com>\r\nFrom: alice@example.com\r\n\r\n"), "bounce@example.com");
+        assert_eq!(sender(b"From: Alice <alice@example.com>\r\n\r\n"), "alice@example.com");
+        assert_eq!(sender(b"Return-Path: <>\r\nFrom: undisclosed-recipients:;\r\n\r\n"), UNKNOWN_SENDER);
+        assert_eq!(sender(b"Subject: No sender\r\n\r\n"), UNKNOWN_SENDER);
+    }
+
+    #[test]
+    fn mbox_messages_are_quoted_and_separated() {
+        let path = env::temp_dir().join(format!("postkast-export-{}.mbox", process::id()));
+        let mut mbox = Mbox::create(&path).unwrap();
+        let received = DateTime::parse_from_rfc2822("Mon, 7 Feb 1994 21:52:25 -0800").unwrap();
+        mbox.add(b"From: alice@example.com\r\n\r\nFrom here\r\n>From there\r\nno end", Some(received)).unwrap();
+        mbox.flush().unwrap();
+        let written = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(written, "From alice@example.com Tue Feb  8 05:52:25 1994\n\
+            From: alice@example.com\n\n>From here\n>>From there\nno end\n\n");
+    }
+}
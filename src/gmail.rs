@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use imap::types::Uid;
+
+/// Capability of the Gmail IMAP extensions (https://developers.google.com/gmail/imap/imap-extensions)
+pub const CAPABILITY: &str = "X-GM-EXT-1";
+
+/// Attributes of the fetched Gmail thread IDs and labels
+pub const FETCH_ATTRIBUTES: &str = "(X-GM-THRID X-GM-LABELS)";
+
+/// Gmail specific attributes of a message
+#[derive(Debug, Default, PartialEq)]
+pub struct GmailAttributes {
+    /// Conversation thread ID, X-GM-THRID
+    pub thread_id: Option<u64>,
+    /// Labels of the message, X-GM-LABELS, with system labels like `\Inbox` starting with a backslash
+    pub labels: Vec<String>,
+}
+
+/// The IMAP search criteria of a Gmail search `query`, in the syntax of the Gmail search box
+pub fn search_criteria(query: &[String]) -> Vec<String> {
+    vec!["X-GM-RAW".to_string(), query.join(" ")]
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    /// Atom, number or quoted string
+    Value(String),
+}
+
+/// Tokens of a response line: parentheses, atoms and quoted strings with their escapes removed
+fn tokens(line: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '"' => {
+                let mut value = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => value.extend(chars.next()),
+                        '"' => break,
+                        c => value.push(c),
+                    }
+                }
+                tokens.push(Token::Value(value));
+            },
+            c if c.is_whitespace() => {},
+            c => {
+                let mut value = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' {
+                        break;
+                    }
+                    value.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Value(value));
+            },
+        }
+    }
+    tokens
+}
+
+/// The Gmail attributes by UID in the raw response to a UID FETCH of `FETCH_ATTRIBUTES`,
+/// which the IMAP parser does not know
+pub fn parse_fetch_response(response: &[u8]) -> HashMap<Uid, GmailAttributes> {
+    let mut attributes = HashMap::new();
+    for line in String::from_utf8_lossy(response).lines() {
+        let items = match line.strip_prefix("* ").and_then(|line| line.split_once(" FETCH ")) {
+            Some((_, items)) => items,
+            None => continue,
+        };
+        let mut tokens = tokens(items).into_iter();
+        let (mut uid, mut message) = (None, GmailAttributes::default());
+        while let Some(token) = tokens.next() {
+            let key = match token {
+                Token::Value(key) => key.to_ascii_uppercase(),
+                _ => continue,
+            };
+            match key.as_str() {
+                "UID" => uid = tokens.next().and_then(|token| number(&token)).and_then(|uid| Uid::try_from(uid).ok()),
+                "X-GM-THRID" => message.thread_id = tokens.next().and_then(|token| number(&token)),
+                "X-GM-LABELS" if tokens.next() == Some(Token::Open) => {
+                    for token in tokens.by_ref() {
+                        match token {
+                            Token::Value(label) => message.labels.push(label),
+                            _ => break,
+                        }
+                    }
+                },
+                _ => {},
+            }
+        }
+        if let Some(uid) = uid {
+            attributes.insert(uid, message);
+        }
+    }
+    attributes
+}
+
+fn number(token: &Token) -> Option<u64> {
+    match token {
+        Token::Value(value) => value.parse().ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thread_ids_and_labels_are_parsed_by_uid() {
+        let response = b"* 1 FETCH (X-GM-THRID 1278455344230334865 X-GM-LABELS (\\Inbox \\Important \"Work \\\"2024\\\"\") UID 42)\r\n\
+            * 2 FETCH (UID 43 X-GM-THRID 1278455344230334866 X-GM-LABELS ())\r\n\
+            a1 OK Success\r\n";
+        let attributes = parse_fetch_response(response);
+        assert_eq!(attributes.len(), 2);
+        assert_eq!(attributes[&42], GmailAttributes {
+            thread_id: Some(1278455344230334865),
+            labels: vec!["\\Inbox".to_string(), "\\Important".to_string(), "Work \"2024\"".to_string()],
+        });
+        assert_eq!(attributes[&43], GmailAttributes { thread_id: Some(1278455344230334866), labels: Vec::new() });
+    }
+
+    #[test]
+    fn search_query_is_a_single_argument() {
+        let query = vec!["has:attachment".to_string(), "larger:5M".to_string()];
+        assert_eq!(search_criteria(&query), vec!["X-GM-RAW", "has:attachment larger:5M"]);
+    }
+}
@@ -1,115 +1,899 @@
+mod args;
+mod attachments;
+mod batch;
+mod export;
+mod gmail;
+mod message;
+mod mime;
+mod pool;
+mod pop3;
+mod progress;
+mod render;
+mod reply;
+mod retry;
+mod search;
+mod session;
 mod settings;
+mod smtp;
+mod socks5;
+mod state;
+mod stream;
+mod structure;
+mod threading;
 
 extern crate directories;
 extern crate config;
 extern crate serde;
 extern crate imap;
 extern crate imap_proto;
+extern crate structopt;
+#[macro_use]
+extern crate log;
 
-use std::net::TcpStream;
-use std::str::{Utf8Error, from_utf8};
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process;
+use std::str::Utf8Error;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
 
-use imap::{Client, Error::*, Error};
-use native_tls::TlsStream;
-use imap_proto::types::Address;
+use chrono::{DateTime, FixedOffset};
+use imap::{Error::*, Error};
+use imap::types::{Flag, Mailbox, NameAttribute, StatusAttribute, Uid, UnsolicitedResponse};
+use imap_proto::types::SectionPath;
+use mailparse::MailHeaderMap;
+use native_tls::{Certificate, TlsConnector};
 
+use structopt::StructOpt;
+
+use args::{Args, Command, Field, Format};
+use batch::uid_set;
+use message::{DisplayAddresses, Message};
+use pool::SessionPool;
+use progress::Progress;
+use render::{render, Layout};
+use reply::Reply;
+use session::ImapSession;
 use settings::Credentials;
 
-use crate::settings::{Settings, Server};
-use ConnectionError::{EncodingError, ConfigError, ImapError};
+use crate::settings::{AddressFamily, Proxy, Settings, Server, Tls};
+use ConnectionError::{AuthError, EncodingError, ConfigError, ImapError, IoError, Pop3Error, SmtpError, TimeoutError, TlsError};
+
+/// Exit status when every server failed
+const EXIT_FAILED: i32 = 1;
+
+/// Exit status when every server failed by rejecting the configured credentials
+const EXIT_AUTHENTICATION_FAILED: i32 = 2;
+
+/// Exit status when some of the servers failed and the others succeeded
+const EXIT_PARTIALLY_FAILED: i32 = 3;
 
+/// Exit status when interrupted with Ctrl-C
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// Longest time to keep an IDLE command running, RFC 2177 asks for re-issuing it within 29 minutes
+const IDLE_REFRESH_LIMIT: Duration = Duration::from_secs(29 * 60);
+
+/// Set on Ctrl-C to ask long running loops to log out and finish
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug)]
 enum ConnectionError {
     // Error in the configuration
     ConfigError(String),
     // Error from Imap
     ImapError(imap::Error),
+    // Server rejected the login credentials
+    AuthError(String),
+    // Server did not respond in time
+    TimeoutError(String),
     // Utf8 encoding error
-    EncodingError(Utf8Error)
+    EncodingError(Utf8Error),
+    // Error reading or writing local files
+    IoError(io::Error),
+    // Error from SMTP
+    SmtpError(lettre::transport::smtp::Error),
+    // Error response from a POP3 server
+    Pop3Error(String),
+    // TLS could not be set up
+    TlsError(native_tls::Error),
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError(msg) => write!(f, "Configuration error: {:}", msg),
+            ImapError(e) => write!(f, "IMAP error: {:}", e),
+            AuthError(msg) | TimeoutError(msg) => f.write_str(msg),
+            EncodingError(e) => write!(f, "Invalid UTF-8 from the server: {:}", e),
+            IoError(e) => write!(f, "IO error: {:}", e),
+            SmtpError(e) => write!(f, "SMTP error: {:}", e),
+            Pop3Error(msg) => write!(f, "POP3 error: {:}", msg),
+            TlsError(e) => write!(f, "TLS error: {:}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConnectionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ImapError(e) => Some(e),
+            EncodingError(e) => Some(e),
+            IoError(e) => Some(e),
+            SmtpError(e) => Some(e),
+            TlsError(e) => Some(e),
+            ConfigError(_) | AuthError(_) | TimeoutError(_) | Pop3Error(_) => None,
+        }
+    }
 }
 
 impl From<imap::Error> for ConnectionError {
     fn from(e: Error) -> Self { ImapError(e) }
 }
 
-impl From<(imap::Error, Client<TlsStream<TcpStream>>)> for ConnectionError {
-    fn from(e: (Error, Client<TlsStream<TcpStream>>)) -> Self { ImapError(e.0) }
+impl From<lettre::transport::smtp::Error> for ConnectionError {
+    fn from(e: lettre::transport::smtp::Error) -> Self { SmtpError(e) }
+}
+
+impl From<lettre::address::AddressError> for ConnectionError {
+    fn from(e: lettre::address::AddressError) -> Self { ConfigError(format!("Invalid email address: {:}", e)) }
+}
+
+impl From<lettre::error::Error> for ConnectionError {
+    fn from(e: lettre::error::Error) -> Self { ConfigError(format!("Cannot build message: {:}", e)) }
+}
+
+impl From<io::Error> for ConnectionError {
+    fn from(e: io::Error) -> Self { IoError(e) }
 }
 
 impl From<Utf8Error> for ConnectionError {
     fn from(e: Utf8Error) -> Self { EncodingError(e) }
 }
 
-fn print_addresses(head: &str, addresses: &Vec<Address>) {
-    print!("{:}", head);
-    for address in addresses {
-        print!("(");
-        let decode_to_str = |bytes| from_utf8(bytes).ok();
-        if let Some(name) = address.name.and_then(decode_to_str) { print!("\"{:}\"", name) } else { print!("NIL") }
-        print!(" ");
-        if let Some(adl) = address.adl.and_then(decode_to_str) { print!("\"{:}\"", adl) } else { print!("NIL") }
-        print!(" ");
-        if let Some(mailbox) = address.mailbox.and_then(decode_to_str) { print!("\"{:}\"", mailbox) } else { print!("NIL") }
-        print!(" ");
-        if let Some(host) = address.host.and_then(decode_to_str) { print!("\"{:}\"", host) } else { print!("NIL") }
-        print!("), ");
-    }
-    println!();
-}
-
-fn list_inbox(server: &Server) -> Result<(), ConnectionError> {
-    println!("Connecting to server \"{:}\"", server.name());
-    println!("IMAP host: {:}", server.imap().host());
-    println!("     port: {:}", server.imap().port());
-
-    let credentials = server.credentials();
-    let name = server.name();
-
-    let server = server.imap();
-    let domain = server.host();
-    let port = server.port();
-    let client = server.tls()
-        .ok_or_else(|| ConfigError(format!("No TLS configured for '{:}'", name)))
-        .and_then(|_| {
-            let tls = native_tls::TlsConnector::builder().build().unwrap();        
-            imap::connect((domain, port), domain, &tls).map_err(ConnectionError::from)
-        })?;
-
-
-    // the client we have here is unauthenticated.
-    // to do anything useful with the e-mails, we need to log in
-    let mut imap_session = match credentials {
-        Credentials::UsernameAndPassword { username, password } => client.login(username, password)?,
-        Credentials::None => return Err(ConfigError(format!("No username and password configured for '{:?}'", name))),
-    };
+impl From<native_tls::Error> for ConnectionError {
+    fn from(e: native_tls::Error) -> Self { TlsError(e) }
+}
+
+/// Mailbox name and attributes as reported by the LIST command
+struct MailboxInfo {
+    name: String,
+    attributes: Vec<String>,
+}
+
+impl ConnectionError {
+    /// Whether the error is likely to go away when the connection is attempted again
+    fn is_transient(&self) -> bool {
+        match self {
+            ImapError(Io(err)) | IoError(err) => matches!(err.kind(),
+                io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::Interrupted),
+            ImapError(TlsHandshake(_)) | ImapError(ConnectionLost) | TimeoutError(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the error is caused by the server not responding within the timeout
+    fn is_timeout(&self) -> bool {
+        match self {
+            ImapError(Io(err)) | IoError(err) => matches!(err.kind(),
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock),
+            _ => false,
+        }
+    }
+}
+
+/// TLS connector trusting the system roots and the additional root certificate of `tls`, if any
+fn tls_connector(tls: Option<&Tls>) -> Result<TlsConnector, ConnectionError> {
+    let mut builder = TlsConnector::builder();
+    if let Some(tls) = tls {
+        if let Some(path) = tls.ca_cert() {
+            let pem = fs::read(path)
+                .map_err(|err| ConfigError(format!("Cannot read CA certificate {:?}: {:}", path, err)))?;
+            let cert = Certificate::from_pem(&pem)
+                .map_err(|err| ConfigError(format!("Invalid CA certificate {:?}: {:}", path, err)))?;
+            builder.add_root_certificate(cert);
+        }
+        builder.danger_accept_invalid_certs(tls.danger_accept_invalid_certs());
+    }
+    Ok(builder.build()?)
+}
+
+/// Open a TCP connection to the server with the configured connect, read and write timeouts,
+/// through the SOCKS5 `proxy` if one is configured
+fn open_stream(host: &str, port: u16, timeout: Duration, proxy: Option<&Proxy>) -> io::Result<TcpStream> {
+    if let Some(proxy) = proxy {
+        // the proxy resolves the host name, so that DNS lookups do not bypass it
+        let mut stream = connect_first(proxy.host(), (proxy.host(), proxy.port()), AddressFamily::Auto, timeout)?;
+        socks5::handshake(&mut stream, host, port, proxy.login())?;
+        return Ok(stream);
+    }
+
+    connect_first(host, (host, port), AddressFamily::Auto, timeout)
+}
+
+/// Resolve the `addresses` of `host` once and connect to the first one accepting the connection,
+/// so that unreachable addresses of round-robin DNS are skipped. Only addresses of the `family` are tried.
+/// Fails with the errors of all of them.
+fn connect_first(host: &str, addresses: impl ToSocketAddrs, family: AddressFamily, timeout: Duration) -> io::Result<TcpStream> {
+    let mut errors = Vec::new();
+    let mut kind = io::ErrorKind::NotFound;
+    for address in addresses.to_socket_addrs()?.filter(|address| family.includes(address)) {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                return Ok(stream);
+            },
+            Err(err) => {
+                debug!("Cannot connect to {:} at {:}: {:}", host, address, err);
+                kind = err.kind();
+                errors.push(format!("{:}: {:}", address, err));
+            },
+        }
+    }
+    match errors.len() {
+        0 => Err(io::Error::new(kind, match family {
+            AddressFamily::Auto => format!("Cannot resolve host {:}", host),
+            AddressFamily::Ipv4 => format!("Host {:} has no IPv4 address", host),
+            AddressFamily::Ipv6 => format!("Host {:} has no IPv6 address", host),
+        })),
+        1 => Err(io::Error::new(kind, format!("Cannot connect to {:} at {:}", host, errors[0]))),
+        _ => Err(io::Error::new(kind, format!("Cannot connect to any address of {:} ({:})", host, errors.join("; ")))),
+    }
+}
+
+/// A `NO` response to a login means the credentials of the server `name` were rejected
+fn login_error(name: &str, error: Error) -> ConnectionError {
+    match error {
+        No(msg) => AuthError(format!("Invalid credentials for {:}: {:}", name, msg)),
+        error => ImapError(error),
+    }
+}
+
+/// Look up the password of `username` for `service` from the system keyring
+fn keyring_password(name: &str, service: &str, username: &str) -> Result<String, ConnectionError> {
+    keyring::Entry::new(service, username)
+        .and_then(|entry| entry.get_password())
+        .map_err(|err| ConfigError(format!("Cannot read password for '{:}' from keyring: {:}", name, err)))
+}
+
+/// Run the `command` with the shell and take its output, without the trailing newline, as the password
+fn command_password(name: &str, command: &str) -> Result<String, ConnectionError> {
+    let shell = if cfg!(windows) { ["cmd", "/C"] } else { ["sh", "-c"] };
+    let output = process::Command::new(shell[0]).arg(shell[1]).arg(command)
+        .stderr(process::Stdio::inherit())
+        .output()
+        .map_err(|err| ConfigError(format!("Cannot run password command for '{:}': {:}", name, err)))?;
+    if !output.status.success() {
+        return Err(ConfigError(format!("Password command for '{:}' failed: {:}", name, output.status)));
+    }
+    let password = String::from_utf8(output.stdout)
+        .map_err(|_| ConfigError(format!("Password command for '{:}' printed invalid UTF-8", name)))?;
+    Ok(password.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+fn store_password(server: &Server) -> Result<(), ConnectionError> {
+    match server.credentials() {
+        Credentials::Keyring { username, service } => {
+            let prompt = format!("Password for {:} ({:}): ", username, service);
+            let password = rpassword::read_password_from_tty(Some(&prompt))
+                .map_err(|err| ConfigError(format!("Cannot read password: {:}", err)))?;
+            keyring::Entry::new(service, username)
+                .and_then(|entry| entry.set_password(&password))
+                .map_err(|err| ConfigError(format!("Cannot store password in keyring: {:}", err)))
+        },
+        _ => Err(ConfigError(format!("Server '{:}' is not configured to use the keyring", server.name()))),
+    }
+}
+
+fn list_mailboxes(server: &Server, retries: u32) -> Result<Vec<MailboxInfo>, ConnectionError> {
+    let mut imap_session = ImapSession::connect(server, retries, false)?;
+
+    let names = imap_session.list(Some(""), Some("*"))?;
+    let mailboxes = names.iter()
+        .map(|name| MailboxInfo {
+            name: name.name().to_string(),
+            attributes: name.attributes().iter().map(|attribute| match attribute {
+                NameAttribute::NoInferiors => "\\Noinferiors".to_string(),
+                NameAttribute::NoSelect => "\\Noselect".to_string(),
+                NameAttribute::Marked => "\\Marked".to_string(),
+                NameAttribute::Unmarked => "\\Unmarked".to_string(),
+                NameAttribute::Custom(custom) => custom.to_string(),
+            }).collect(),
+        })
+        .collect();
 
-    // we want to fetch the first email in the INBOX mailbox
-    imap_session.select("INBOX")?;
-    
+    // be nice to the server and log out
+    imap_session.logout()?;
+
+    Ok(mailboxes)
+}
+
+/// Message counts of a mailbox
+struct FolderStatus {
+    total: u32,
+    recent: u32,
+    unseen: Option<u32>,
+}
+
+fn folder_status(server: &Server, folder: &str, retries: u32) -> Result<FolderStatus, ConnectionError> {
+    let mut imap_session = ImapSession::connect(server, retries, false)?;
+
+    // SELECT only reports the sequence number of the first unseen message,
+    // so ask for the counts with STATUS instead. This does not touch any messages.
+    imap_session.status(folder, "(MESSAGES RECENT UNSEEN)")?;
 
-    // fetch message number 1 in this mailbox, along with its RFC822 field.
-    // RFC 822 dictates the format of the body of e-mails
-    let messages = imap_session.fetch("1:100", "ALL")?;
-    for message in messages.iter() {
-        println!("---");
-        if let Some(envelope) = message.envelope() {
-            if let Some(from) = &envelope.from {
-                print_addresses("From: ", from);
+    let mut status = FolderStatus { total: 0, recent: 0, unseen: None };
+    for response in imap_session.unsolicited_responses() {
+        if let UnsolicitedResponse::Status { attributes, .. } = response {
+            for attribute in attributes {
+                match attribute {
+                    StatusAttribute::Messages(total) => status.total = total,
+                    StatusAttribute::Recent(recent) => status.recent = recent,
+                    StatusAttribute::Unseen(unseen) => status.unseen = Some(unseen),
+                    _ => {},
+                }
             }
-            if let Some(to) = &envelope.to {
-                print_addresses("To: ", to);
+        }
+    }
+
+    // be nice to the server and log out
+    imap_session.logout()?;
+
+    Ok(status)
+}
+
+/// The whole message `uid` of `folder`, without marking it read
+fn fetch_raw_message(server: &Server, folder: &str, uid: Uid, retries: u32) -> Result<Vec<u8>, ConnectionError> {
+    let mut imap_session = ImapSession::connect(server, retries, false)?;
+    imap_session.select(folder)?;
+
+    let fetched = imap_session.uid_fetch(uid.to_string(), "BODY.PEEK[]")?;
+    let message = fetched.iter().find_map(|fetch| fetch.body()).map(<[u8]>::to_vec);
+
+    // be nice to the server and log out
+    imap_session.logout()?;
+
+    message.ok_or_else(|| ConfigError(format!("No message with UID {:} in {:}", uid, folder)))
+}
+
+/// The MIME parts of the message `uid` of `folder`, from its BODYSTRUCTURE
+fn fetch_structure(server: &Server, folder: &str, uid: Uid, retries: u32) -> Result<Vec<structure::Part>, ConnectionError> {
+    let mut imap_session = ImapSession::connect(server, retries, false)?;
+    imap_session.select(folder)?;
+
+    let fetched = imap_session.uid_fetch(uid.to_string(), "BODYSTRUCTURE")?;
+    let parts = fetched.iter().find_map(|fetch| fetch.bodystructure()).map(structure::parts);
+
+    // be nice to the server and log out
+    imap_session.logout()?;
+
+    parts.ok_or_else(|| ConfigError(format!("No message with UID {:} in {:}", uid, folder)))
+}
+
+/// The MIME part `section` of the message `uid` of `folder`, decoded by its transfer encoding and charset.
+/// The structure is fetched first, so that only the part itself is downloaded.
+fn fetch_part(server: &Server, folder: &str, uid: Uid, section: &str, retries: u32) -> Result<Vec<u8>, ConnectionError> {
+    let path: Vec<u32> = section.split('.').map(str::parse).collect::<Result<_, _>>()
+        .ok().filter(|path: &Vec<u32>| !path.contains(&0))
+        .ok_or_else(|| ConfigError(format!("Invalid part number '{:}', expected e.g. 1 or 2.1", section)))?;
+
+    let mut imap_session = ImapSession::connect(server, retries, false)?;
+    imap_session.select(folder)?;
+
+    let fetched = imap_session.uid_fetch(uid.to_string(), "BODYSTRUCTURE")?;
+    let parts = match fetched.iter().find_map(|fetch| fetch.bodystructure()).map(structure::parts) {
+        Some(parts) => parts,
+        None => {
+            imap_session.logout()?;
+            return Err(ConfigError(format!("No message with UID {:} in {:}", uid, folder)));
+        },
+    };
+    let part = match parts.into_iter().find(|part| part.number == section) {
+        Some(part) => part,
+        None => {
+            imap_session.logout()?;
+            return Err(ConfigError(format!("Message UID {:} in {:} has no part {:}, see the structure command for its parts", uid, folder, section)));
+        },
+    };
+    let fetched = imap_session.uid_fetch(uid.to_string(), format!("BODY.PEEK[{:}]", section))?;
+    let data = fetched.iter().find_map(|fetch| fetch.section(&SectionPath::Part(path.clone(), None))).map(|data| part.decode(data));
+
+    // be nice to the server and log out
+    imap_session.logout()?;
+
+    data.ok_or_else(|| ConfigError(format!("The server returned no part {:} of UID {:} in {:}", section, uid, folder)))
+}
+
+/// Fail unless the raw `message` starts with parseable header fields, including a From header
+fn check_headers(message: &[u8]) -> Result<(), String> {
+    let (headers, _) = mailparse::parse_headers(message).map_err(|err| format!("Cannot parse the message headers: {:}", err))?;
+    if headers.get_first_header("From").is_none() {
+        return Err("The message has no From header".to_string());
+    }
+    Ok(())
+}
+
+/// Upload the raw `message` into `folder`, returning its UID when it can be found.
+///
+/// The imap crate drops the APPENDUID response code of servers with the UIDPLUS extension (RFC 4315),
+/// so the UID is looked up by the Message-ID among the messages added to the folder since.
+fn append_message(server: &Server, folder: &str, message: &[u8], flags: &[Flag], date: Option<DateTime<FixedOffset>>, args: &Args) -> Result<Option<Uid>, ConnectionError> {
+    let mut imap_session = ImapSession::connect(server, args.retries, args.write)?;
+    if args.dry_run {
+        info!("Dry run, not appending the message to {:}", folder);
+        imap_session.logout()?;
+        return Ok(None);
+    }
+
+    let uid_next = imap_session.select(folder)?.uid_next;
+    imap_session.append(folder, message, flags, date)?;
+    let message_id = mailparse::parse_headers(message).ok()
+        .and_then(|(headers, _)| headers.get_first_value("Message-ID"))
+        .map(|id| id.trim().replace('\\', "\\\\").replace('"', "\\\""));
+    let uid = match (uid_next, message_id) {
+        (Some(uid_next), Some(message_id)) => imap_session
+            .uid_search(format!("UID {:}:* HEADER Message-ID \"{:}\"", uid_next, message_id))?
+            .into_iter().filter(|&uid| uid >= uid_next).max(),
+        _ => None,
+    };
+
+    // be nice to the server and log out
+    imap_session.logout()?;
+
+    Ok(uid)
+}
+
+/// Add and remove flags of the message `uid` in `folder`, returning its updated flags
+fn flag_message(server: &Server, folder: &str, uid: Uid, add: &[&str], remove: &[&str], args: &Args) -> Result<Vec<String>, ConnectionError> {
+    let mut imap_session = ImapSession::connect(server, args.retries, args.write)?;
+    imap_session.select(folder)?;
+
+    let uid = uid.to_string();
+    let current = imap_session.uid_fetch(&uid, "FLAGS")?;
+    if current.is_empty() {
+        imap_session.logout()?;
+        return Err(ConfigError(format!("No message with UID {:} in {:}", uid, folder)));
+    }
+    if args.dry_run {
+        let mut flags: Vec<String> = current.iter()
+            .flat_map(|message| message.flags().iter().map(|flag| flag.to_string()))
+            .filter(|flag| !remove.contains(&flag.as_str()))
+            .collect();
+        for flag in add {
+            if !flags.iter().any(|set| set == flag) {
+                flags.push(flag.to_string());
             }
-            if let Some(cc) = &envelope.cc {
-                print_addresses("Cc: ", cc);
+        }
+        info!("Dry run, not changing the flags of UID {:} in {:}", uid, folder);
+        imap_session.logout()?;
+        return Ok(flags);
+    }
+    if !add.is_empty() {
+        imap_session.uid_store(&uid, &format!("+FLAGS ({:})", add.join(" ")))?;
+    }
+    if !remove.is_empty() {
+        imap_session.uid_store(&uid, &format!("-FLAGS ({:})", remove.join(" ")))?;
+    }
+    let flags = imap_session.uid_fetch(&uid, "FLAGS")?.iter()
+        .flat_map(|message| message.flags().iter().map(|flag| flag.to_string()))
+        .collect();
+
+    // be nice to the server and log out
+    imap_session.logout()?;
+
+    Ok(flags)
+}
+
+/// Whether `uids` is a well-formed UID set, such as `42`, `40:45`, `1,3,5` or `100:*`
+fn is_uid_set(uids: &str) -> bool {
+    let is_uid = |uid: &str| uid == "*" || (!uid.is_empty() && uid.bytes().all(|b| b.is_ascii_digit()));
+    uids.split(',').all(|range| range.splitn(2, ':').all(is_uid))
+}
+
+/// Move or copy the messages `uids` from `source` to `destination`.
+///
+/// Servers without the MOVE extension get the messages copied, flagged as deleted
+/// and expunged from the source folder instead.
+fn transfer_messages(server: &Server, source: &str, uids: &str, destination: &str, move_messages: bool, args: &Args) -> Result<(), ConnectionError> {
+    if !is_uid_set(uids) {
+        return Err(ConfigError(format!("Invalid UID set '{:}'", uids)));
+    }
+
+    let mut imap_session = ImapSession::connect(server, args.retries, args.write)?;
+    if imap_session.list(Some(""), Some(destination))?.is_empty() {
+        imap_session.logout()?;
+        return Err(ConfigError(format!("Folder '{:}' does not exist on \"{:}\"", destination, server.name())));
+    }
+    imap_session.select(source)?;
+
+    if args.dry_run {
+        info!("Dry run, not {:} {:} from {:} to {:}", if move_messages { "moving" } else { "copying" },
+            uids, source, destination);
+    } else if !move_messages {
+        imap_session.uid_copy(uids, destination)?;
+    } else {
+        if imap_session.has_capability("MOVE")? {
+            imap_session.uid_mv(uids, destination)?;
+        } else {
+            let uidplus = check_expunge(&mut imap_session, source, uids)?;
+            imap_session.uid_copy(uids, destination)?;
+            imap_session.uid_store(uids, "+FLAGS.SILENT (\\Deleted)")?;
+            expunge_messages(&mut imap_session, uids, uidplus)?;
+        }
+    }
+
+    // be nice to the server and log out
+    imap_session.logout()?;
+
+    Ok(())
+}
+
+/// Whether the messages `uids` of the selected `folder` can be expunged by UID with the UIDPLUS extension (RFC 4315).
+///
+/// Without it only the whole folder can be expunged, removing every message flagged as deleted,
+/// which fails when messages other than `uids` are flagged as deleted already.
+fn check_expunge(imap_session: &mut ImapSession, folder: &str, uids: &str) -> Result<bool, ConnectionError> {
+    if imap_session.has_capability("UIDPLUS")? {
+        return Ok(true);
+    }
+    let targets = imap_session.uid_search(format!("UID {:}", uids))?;
+    let others = imap_session.uid_search("DELETED")?.difference(&targets).count();
+    if others > 0 {
+        imap_session.logout()?;
+        return Err(ConfigError(format!("Cannot expunge only {:} from {:}: \"{:}\" has no UIDPLUS support \
+            and {:} other message(s) are flagged as deleted", uids, folder, imap_session.server_name(), others)));
+    }
+    Ok(false)
+}
+
+/// Expunge the messages `uids` flagged as deleted, by UID when the server supports `uidplus`
+/// and otherwise with the whole selected folder, as checked by `check_expunge`
+fn expunge_messages(imap_session: &mut ImapSession, uids: &str, uidplus: bool) -> Result<(), ConnectionError> {
+    if uidplus {
+        imap_session.uid_expunge(uids)?;
+    } else {
+        imap_session.expunge()?;
+    }
+    Ok(())
+}
+
+/// Flag the messages `uids` in `folder` as deleted and, if asked to, expunge them.
+fn delete_messages(server: &Server, folder: &str, uids: &str, expunge: bool, args: &Args) -> Result<(), ConnectionError> {
+    if !is_uid_set(uids) {
+        return Err(ConfigError(format!("Invalid UID set '{:}'", uids)));
+    }
+
+    let mut imap_session = ImapSession::connect(server, args.retries, args.write)?;
+    imap_session.select(folder)?;
+
+    if args.dry_run {
+        info!("Dry run, not deleting {:} from {:}", uids, folder);
+        imap_session.logout()?;
+        return Ok(());
+    }
+    let uidplus = if expunge { Some(check_expunge(&mut imap_session, folder, uids)?) } else { None };
+    imap_session.uid_store(uids, "+FLAGS.SILENT (\\Deleted)")?;
+    if let Some(uidplus) = uidplus {
+        expunge_messages(&mut imap_session, uids, uidplus)?;
+    }
+
+    // be nice to the server and log out
+    imap_session.logout()?;
+
+    Ok(())
+}
+
+/// Whether Ctrl-C was pressed, asking to log out and finish without starting any more work
+fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// Wait for `interval`, returning early when a shutdown is requested
+fn sleep_unless_shutdown(interval: Duration) {
+    let step = Duration::from_secs(1);
+    let mut slept = Duration::from_secs(0);
+    while slept < interval && !shutdown_requested() {
+        thread::sleep(step.min(interval - slept));
+        slept += step;
+    }
+}
+
+/// Print the messages arriving in `folder` until a shutdown is requested.
+///
+/// Servers supporting IDLE notify about new messages as they arrive, with the IDLE command
+/// re-issued every `interval`. Other servers are polled with NOOP every `interval`.
+/// New messages are printed directly to stdout instead of `out`, as they arrive.
+fn watch_folder(server: &Server, folder: &str, interval: Duration, args: &Args) -> Result<(), ConnectionError> {
+    let mut imap_session = ImapSession::connect(server, args.retries, args.write)?;
+    let idle = imap_session.has_capability("IDLE")?;
+    let mailbox = imap_session.select(folder)?;
+
+    let mut next_uid = match mailbox.uid_next {
+        Some(uid_next) => uid_next,
+        None => imap_session.uid_search("ALL")?.into_iter().max().map_or(1, |uid| uid + 1),
+    };
+    let interval = interval.min(IDLE_REFRESH_LIMIT);
+    info!("Watching {:}/{:} for new messages{:}", server.name(), folder,
+        if idle { "" } else { " (polling, server does not support IDLE)" });
+
+    while !shutdown_requested() {
+        if idle {
+            imap_session.idle()?.wait_with_timeout(interval)?;
+        } else {
+            sleep_unless_shutdown(interval);
+            imap_session.noop()?;
+        }
+
+        // UID SEARCH n:* always matches the newest message, even when its UID is below n
+        let mut uids: Vec<Uid> = imap_session.uid_search(format!("{:}:*", next_uid))?.into_iter()
+            .filter(|&uid| uid >= next_uid)
+            .collect();
+        uids.sort_unstable();
+        if let Some(&last) = uids.last() {
+            next_uid = last + 1;
+            let messages = fetch_messages(&mut imap_session, &uids, args)?;
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            if !args.quiet {
+                writeln!(stdout, "New messages in {:}/{:}", server.name(), folder)?;
             }
-            if let Some(bcc) = &envelope.bcc {
-                print_addresses("Bcc: ", bcc);
+            render(&messages, args.format, &Layout::new(args), &mut stdout)?;
+            stdout.flush()?;
+        }
+    }
+
+    // be nice to the server and log out
+    imap_session.logout()?;
+
+    Ok(())
+}
+
+/// List the messages of `folder` with their numbers and print the message whose number is read from `input`,
+/// over a single session logged in again if lost while waiting, until the input ends.
+/// The listing and messages are written to `out` as they come, which is the terminal rather than the buffered output.
+fn browse_folder(server: &Server, folder: &str, args: &Args, input: &mut dyn BufRead, out: &mut dyn Write) -> Result<(), ConnectionError> {
+    let mut pool = SessionPool::new(args.retries);
+    let messages = list_folder(pool.session(server)?, folder, args)?;
+    let print_listing = |out: &mut dyn Write| -> io::Result<()> {
+        for (number, message) in messages.iter().enumerate() {
+            let from = message.from.as_deref().map(|from| DisplayAddresses(from).to_string()).unwrap_or_default();
+            writeln!(out, "{:>4}  {:}  ({:})", number + 1, message.subject.as_deref().unwrap_or("(no subject)"), from)?;
+        }
+        Ok(())
+    };
+
+    if messages.is_empty() {
+        writeln!(out, "No messages in {:}", folder)?;
+    }
+    print_listing(out)?;
+    let mut lines = input.lines();
+    while !shutdown_requested() {
+        write!(out, "Message number, 'l' to list again or Ctrl-D to quit: ")?;
+        out.flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => {
+                writeln!(out)?;
+                break;
+            },
+        };
+        let message = match line.trim() {
+            "" => continue,
+            "l" => {
+                print_listing(out)?;
+                continue;
+            },
+            number => match number.parse::<usize>().ok().and_then(|number| messages.get(number.wrapping_sub(1))) {
+                Some(message) => message,
+                None => {
+                    writeln!(out, "No message number '{:}', expected 1 to {:}", number, messages.len())?;
+                    continue;
+                },
+            },
+        };
+        let uid = message.uid.expect("listed messages have UIDs");
+        let fetched = pool.session(server)?.uid_fetch(uid.to_string(), "BODY.PEEK[]")?;
+        match fetched.iter().find_map(|fetch| fetch.body()).and_then(|raw| Message::from_rfc822(raw, true)) {
+            Some(mut full) => {
+                full.uid = Some(uid);
+                render(&[full], Format::Text, &Layout::new(args), out)?;
+            },
+            None => writeln!(out, "Cannot read the message with UID {:}", uid)?,
+        }
+    }
+
+    // the pool logs out when dropped
+    Ok(())
+}
+
+/// Usage and limit of one resource of a quota root, in the units of the resource
+struct QuotaUsage {
+    root: String,
+    resource: String,
+    usage: u64,
+    limit: u64,
+}
+
+/// Capabilities advertised by the server once logged in
+fn server_capabilities(server: &Server, retries: u32) -> Result<Vec<String>, ConnectionError> {
+    let mut imap_session = ImapSession::connect(server, retries, false)?;
+    let capabilities = imap_session.capabilities()?.to_vec();
+
+    // be nice to the server and log out
+    imap_session.logout()?;
+
+    Ok(capabilities)
+}
+
+/// Quota usage of the quota roots of `folder`, or `None` if the server does not support quotas
+fn folder_quota(server: &Server, folder: &str, retries: u32) -> Result<Option<Vec<QuotaUsage>>, ConnectionError> {
+    let mut imap_session = ImapSession::connect(server, retries, false)?;
+    if !imap_session.has_capability("QUOTA")? {
+        imap_session.logout()?;
+        return Ok(None);
+    }
+
+    let folder = folder.replace('\\', "\\\\").replace('"', "\\\"");
+    let response = imap_session.run_raw_command(&format!("GETQUOTAROOT \"{:}\"", folder))?;
+
+    // be nice to the server and log out
+    imap_session.logout()?;
+
+    Ok(Some(parse_quota_response(&response)))
+}
+
+/// Resources of the `* QUOTA <root> (<resource> <usage> <limit> ...)` lines of a raw response
+fn parse_quota_response(response: &[u8]) -> Vec<QuotaUsage> {
+    let mut quotas = Vec::new();
+    for line in String::from_utf8_lossy(response).lines() {
+        let (root, resources) = match line.strip_prefix("* QUOTA ").and_then(|rest| rest.split_once('(')) {
+            Some(quota) => quota,
+            None => continue,
+        };
+        let root = root.trim().trim_matches('"');
+        let resources: Vec<&str> = resources.trim_end().trim_end_matches(')').split_whitespace().collect();
+        for resource in resources.chunks(3) {
+            if let [name, usage, limit] = resource {
+                if let (Ok(usage), Ok(limit)) = (usage.parse(), limit.parse()) {
+                    quotas.push(QuotaUsage { root: root.to_string(), resource: name.to_string(), usage, limit });
+                }
             }
-            if let Some(date) = &envelope.date.and_then(|v| from_utf8(v).ok() ) {
-                println!("Date: {:}", *date);
+        }
+    }
+    quotas
+}
+
+/// Sequence set of the messages to list in the selected `mailbox`. Returns `None` for an
+/// empty mailbox, as some servers answer a search or fetch of a range of nothing with an error.
+fn mailbox_range(mailbox: &Mailbox, args: &Args) -> Option<String> {
+    if mailbox.exists == 0 {
+        return None;
+    }
+    sequence_range(mailbox.exists, args.limit, args.offset)
+}
+
+/// Sequence set of the newest `limit` messages in a mailbox of `exists` messages,
+/// skipping the `offset` newest ones. Returns `None` when there is nothing to fetch.
+fn sequence_range(exists: u32, limit: Option<u32>, offset: u32) -> Option<String> {
+    let last = exists.checked_sub(offset).filter(|&last| last > 0)?;
+    let first = match limit {
+        Some(0) => return None,
+        Some(limit) => last.saturating_sub(limit) + 1,
+        None => 1,
+    };
+    Some(format!("{:}:{:}", first, last))
+}
+
+/// Fetch every message of `folder` in UID order and in batches, handing each one to `save`
+/// with its flags and received date, without marking them read. Returns the number of messages saved.
+fn export_folder<F>(server: &Server, folder: &str, args: &Args, mut save: F) -> Result<usize, ConnectionError>
+where
+    F: FnMut(&[u8], &[String], Option<DateTime<FixedOffset>>) -> io::Result<()>,
+{
+    let mut imap_session = ImapSession::connect(server, args.retries, args.write)?;
+    let mailbox = imap_session.select(folder)?;
+
+    let mut uids: Vec<Uid> = imap_session.uid_search("ALL")?.into_iter().collect();
+    uids.sort_unstable();
+    let label = format!("Exporting {:}/{:}", server.name(), folder);
+    let mut progress = Progress::new(label, mailbox.exists as usize, args.batch_size, Format::Text);
+    let mut count = 0;
+    for (batch, batch_set) in batch::batches(&uids, args.batch_size) {
+        if shutdown_requested() {
+            break;
+        }
+        imap_session.keepalive()?;
+        debug!("Exporting {:} message(s) of {:}/{:}", batch.len(), server.name(), folder);
+        let fetched = imap_session.uid_fetch(batch_set, "(FLAGS INTERNALDATE BODY.PEEK[])")?;
+        let mut fetched: Vec<_> = fetched.iter().filter(|fetch| fetch.body().is_some()).collect();
+        fetched.sort_by_key(|fetch| fetch.uid);
+        for fetch in fetched {
+            let flags: Vec<String> = fetch.flags().iter().map(|flag| flag.to_string()).collect();
+            save(fetch.body().unwrap_or_default(), &flags, fetch.internal_date())?;
+            count += 1;
+        }
+        progress.add(batch.len());
+    }
+    drop(progress);
+
+    // be nice to the server and log out
+    imap_session.logout()?;
+
+    Ok(count)
+}
+
+/// List the messages of each of the requested folders, logging in only once.
+///
+/// Folders the server refuses to select, such as folders that do not exist,
+/// are reported and skipped.
+fn list_folders<'a>(server: &'a Server, args: &'a Args) -> Result<Vec<(&'a str, Vec<Message>)>, ConnectionError> {
+    let mut imap_session = ImapSession::connect(server, args.retries, args.write)?;
+
+    let mut listed = Vec::new();
+    for folder in args.folders(server) {
+        if shutdown_requested() {
+            break;
+        }
+        imap_session.keepalive()?;
+        match list_folder(&mut imap_session, folder, args) {
+            Ok(messages) => listed.push((folder, messages)),
+            Err(ImapError(No(msg))) => error!("Cannot list {:}/{:}: {:}", server.name(), folder, msg),
+            Err(err) => return Err(err),
+        }
+    }
+
+    // be nice to the server and log out
+    imap_session.logout()?;
+
+    Ok(listed)
+}
+
+/// Stream the messages of the requested folders to stdout as NDJSON, one message per line.
+///
+/// Messages are fetched in batches of `--batch-size` and printed as they arrive, so that memory use
+/// stays flat on large mailboxes. Sorting needs all messages at once, so sorted listings are printed
+/// only once all batches are fetched.
+fn stream_folders(server: &Server, args: &Args) -> Result<(), ConnectionError> {
+    let mut imap_session = ImapSession::connect(server, args.retries, args.write)?;
+
+    for folder in args.folders(server) {
+        if shutdown_requested() {
+            break;
+        }
+        let uids = match folder_uids(&mut imap_session, folder, args) {
+            Ok(uids) => uids,
+            Err(ImapError(No(msg))) => {
+                error!("Cannot list {:}/{:}: {:}", server.name(), folder, msg);
+                continue;
+            },
+            Err(err) => return Err(err),
+        };
+        let batch_size = if args.sort.is_some() || args.reverse { uids.len() } else { args.batch_size };
+        for (batch, _) in batch::batches(&uids, batch_size) {
+            if shutdown_requested() {
+                break;
             }
-            if let Some(subject) = &envelope.subject.and_then(|v| from_utf8(v).ok() ) {
-                println!("Subject: {:}", *subject);
+            imap_session.keepalive()?;
+            let mut messages = fetch_messages(&mut imap_session, batch, args)?;
+            for message in &mut messages {
+                message.folder = Some(folder.to_string());
             }
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            render(&messages, Format::Ndjson, &Layout::new(args), &mut stdout)?;
+            stdout.flush()?;
+        }
+        if args.new_only || args.changed_since {
+            remember_last_seen(&imap_session, folder, &uids);
         }
     }
 
@@ -119,29 +903,1063 @@ fn list_inbox(server: &Server) -> Result<(), ConnectionError> {
     Ok(())
 }
 
+/// Select `folder` and look up the UIDs of the messages in the requested range, in ascending order
+fn folder_uids(imap_session: &mut ImapSession, folder: &str, args: &Args) -> Result<Vec<Uid>, ConnectionError> {
+    // we want to fetch the newest emails in the requested mailbox
+    let mailbox = imap_session.select(folder)?;
+    if args.new_only || args.changed_since {
+        check_uid_validity(imap_session, folder);
+    }
+    let condstore = args.changed_since && imap_session.has_capability("CONDSTORE")?;
+    if condstore {
+        let saved = saved_folder_state(imap_session, folder).and_then(|saved| saved.highest_modseq);
+        // look up the current HIGHESTMODSEQ first, so that changes made during the listing show up the next time
+        imap_session.query_highest_modseq(folder)?;
+        match saved {
+            Some(_) if mailbox.exists == 0 => return Ok(Vec::new()),
+            Some(modseq) => return changed_uids(imap_session, modseq),
+            None => {},
+        }
+    }
+    // without CONDSTORE, --changed-since falls back to listing the new messages by UID
+    let uid_based = args.new_only || (args.changed_since && !condstore);
+    let last_seen = if uid_based { saved_folder_state(imap_session, folder).and_then(|saved| saved.last_uid) } else { None };
+
+    if args.has_date_filter() || last_seen.is_some() {
+        if mailbox.exists == 0 {
+            return Ok(Vec::new());
+        }
+        let mut criteria = search::date_criteria(args.since, args.before);
+        if let Some(last_seen) = last_seen {
+            criteria.push(format!("UID {:}:*", last_seen + 1));
+        }
+        // `n:*` always includes the newest message, even when its UID is below `n`
+        let mut uids: Vec<Uid> = imap_session.uid_search(criteria.join(" "))?.into_iter()
+            .filter(|&uid| uid > last_seen.unwrap_or(0))
+            .collect();
+        uids.sort_unstable();
+        // keep the newest matches, skipping the `offset` newest ones
+        uids.truncate(uids.len().saturating_sub(args.offset as usize));
+        if let Some(limit) = args.limit {
+            uids.drain(..uids.len().saturating_sub(limit as usize));
+        }
+        return Ok(uids);
+    }
+
+    // sequence numbers shift as messages come and go, so look up the stable UIDs
+    // of the messages in range and fetch their envelopes by UID
+    let mut uids: Vec<Uid> = match mailbox_range(&mailbox, args) {
+        Some(range) => imap_session.uid_search(range)?.into_iter().collect(),
+        None => Vec::new(),
+    };
+    uids.sort_unstable();
+    Ok(uids)
+}
+
+/// UIDs of the messages of the selected folder added or changed after the modification sequence `modseq`,
+/// with the CHANGEDSINCE fetch modifier of the CONDSTORE extension (RFC 7162), in ascending order
+fn changed_uids(imap_session: &mut ImapSession, modseq: u64) -> Result<Vec<Uid>, ConnectionError> {
+    let fetched = imap_session.uid_fetch("1:*", format!("(UID) (CHANGEDSINCE {:})", modseq))?;
+    let mut uids: Vec<Uid> = fetched.iter().filter_map(|fetch| fetch.uid).collect();
+    uids.sort_unstable();
+    Ok(uids)
+}
+
+/// Forget the state saved for the selected `folder` when its UIDVALIDITY has changed since,
+/// as the saved UIDs then refer to other messages
+fn check_uid_validity(imap_session: &ImapSession, folder: &str) {
+    let name = imap_session.server_name();
+    let changed = match (state::folder_state(name, folder), imap_session.uid_validity()) {
+        (Ok(Some(saved)), Some(uid_validity)) => saved.uid_validity != uid_validity,
+        (Err(err), _) => {
+            warn!("Cannot read the state of {:}/{:}: {:}", name, folder, err);
+            false
+        },
+        _ => false,
+    };
+    if changed {
+        warn!("The UIDs of {:}/{:} have been reset by the server, \
+            UIDs from earlier listings no longer refer to the same messages", name, folder);
+        if let Err(err) = state::set_folder_state(name, folder, None) {
+            warn!("Cannot save the state of {:}/{:}: {:}", name, folder, err);
+        }
+    }
+}
+
+/// State of `folder` saved by a previous run with `--new-only` or `--changed-since`, unless the UIDs have changed since
+fn saved_folder_state(imap_session: &ImapSession, folder: &str) -> Option<state::FolderState> {
+    let saved = match state::folder_state(imap_session.server_name(), folder) {
+        Ok(saved) => saved?,
+        Err(err) => {
+            warn!("Cannot read the last seen message of {:}/{:}: {:}", imap_session.server_name(), folder, err);
+            return None;
+        },
+    };
+    Some(saved).filter(|saved| Some(saved.uid_validity) == imap_session.uid_validity())
+}
+
+/// Remember the newest of the listed `uids` of `folder` and its HIGHESTMODSEQ, if looked up,
+/// for the next run with `--new-only` or `--changed-since`
+fn remember_last_seen(imap_session: &ImapSession, folder: &str, uids: &[Uid]) {
+    let uid_validity = match imap_session.uid_validity() {
+        Some(uid_validity) => uid_validity,
+        None => return,
+    };
+    let saved = saved_folder_state(imap_session, folder);
+    let folder_state = state::FolderState {
+        uid_validity,
+        last_uid: uids.iter().max().copied().max(saved.and_then(|saved| saved.last_uid)),
+        highest_modseq: imap_session.highest_modseq().or(saved.and_then(|saved| saved.highest_modseq)),
+    };
+    if Some(folder_state) == saved {
+        return;
+    }
+    if let Err(err) = state::set_folder_state(imap_session.server_name(), folder, Some(folder_state)) {
+        warn!("Cannot save the last seen message of {:}/{:}: {:}", imap_session.server_name(), folder, err);
+    }
+}
+
+fn list_folder(imap_session: &mut ImapSession, folder: &str, args: &Args) -> Result<Vec<Message>, ConnectionError> {
+    let uids = folder_uids(imap_session, folder, args)?;
+    let mut messages = if uids.is_empty() { Vec::new() } else { fetch_messages(imap_session, &uids, args)? };
+    if args.thread && !messages.is_empty() {
+        messages = thread_messages(imap_session, &uids, messages)?;
+    }
+    for message in &mut messages {
+        message.folder = Some(folder.to_string());
+    }
+    if args.new_only || args.changed_since {
+        remember_last_seen(imap_session, folder, &uids);
+    }
+
+    Ok(messages)
+}
+
+/// The `messages` grouped into conversation threads, by the server when
+/// it supports the THREAD extension (RFC 5256) and from their headers otherwise
+fn thread_messages(imap_session: &mut ImapSession, uids: &[Uid], messages: Vec<Message>) -> Result<Vec<Message>, ConnectionError> {
+    let threads = if imap_session.has_capability("THREAD=REFERENCES")? {
+        let response = imap_session.run_raw_command(&format!("UID THREAD REFERENCES UTF-8 UID {:}", uid_set(uids)))?;
+        threading::uid_threads(threading::parse_thread_response(&response), &messages)
+    } else {
+        threading::build_threads(&messages)
+    };
+    Ok(threading::flatten(messages, &threads))
+}
+
+/// Items to fetch for each listed message; the full message is only needed for its body or attachments,
+/// and only the header fields asked for with `--fields` instead of the whole envelope.
+/// Bodies are always fetched with `BODY.PEEK[]`, which unlike `RFC822` leaves the messages unread.
+fn fetch_query(args: &Args) -> String {
+    let full = args.body || args.save_attachments.is_some();
+    // the received date and size are always fetched and have no header field
+    let mut names: Vec<&str> = args.fields.iter().filter_map(Field::header_name).collect();
+    if names.is_empty() {
+        if !full && !args.thread {
+            return "ALL".to_string();
+        }
+        // the envelope has no References header, which threading needs as well
+        format!("(FLAGS INTERNALDATE RFC822.SIZE ENVELOPE{:}{:})",
+            if args.thread { " BODY.PEEK[HEADER.FIELDS (REFERENCES)]" } else { "" },
+            if full { " BODY.PEEK[]" } else { "" })
+    } else {
+        if args.thread {
+            names.extend(&["Message-ID", "In-Reply-To", "References"]);
+        }
+        format!("(FLAGS INTERNALDATE RFC822.SIZE BODY.PEEK[HEADER.FIELDS ({:})]{:})", names.join(" ").to_uppercase(), if full { " BODY.PEEK[]" } else { "" })
+    }
+}
+
+/// The messages `uids` in the requested order, when sorting was requested and
+/// the server can sort them itself with the SORT extension (RFC 5256)
+fn server_order(imap_session: &mut ImapSession, uids: &[Uid], args: &Args) -> Result<Option<Vec<Uid>>, ConnectionError> {
+    let key = match args.sort {
+        Some(key) if imap_session.has_capability("SORT")? => key,
+        _ => return Ok(None),
+    };
+
+    let command = format!("UID SORT ({:}{:}) UTF-8 UID {:}",
+        if args.reverse { "REVERSE " } else { "" }, key.imap_name(), uid_set(uids));
+    let response = imap_session.run_raw_command(&command)?;
+    Ok(Some(parse_sort_response(&response)))
+}
+
+/// UIDs of the `* SORT` lines of a raw SORT response
+fn parse_sort_response(response: &[u8]) -> Vec<Uid> {
+    String::from_utf8_lossy(response).lines()
+        .filter_map(|line| line.strip_prefix("* SORT"))
+        .flat_map(|uids| uids.split_whitespace().filter_map(|uid| uid.parse().ok()).collect::<Vec<Uid>>())
+        .collect()
+}
+
+/// Put the messages in the order given by the server, or else sort them as requested
+fn order_messages(messages: &mut [Message], args: &Args, server_order: Option<&[Uid]>) {
+    match server_order {
+        Some(order) => messages.sort_by_key(|message| {
+            message.uid.and_then(|uid| order.iter().position(|&sorted| sorted == uid)).unwrap_or(usize::MAX)
+        }),
+        None => message::sort_messages(messages, args.sort, args.reverse, Message::sort_values),
+    }
+}
+
+/// Fetch the messages `uids` in the requested order and in batches of `--batch-size`, saving their attachments
+fn fetch_messages(imap_session: &mut ImapSession, uids: &[Uid], args: &Args) -> Result<Vec<Message>, ConnectionError> {
+    let order = server_order(imap_session, uids, args)?;
+    let gmail = imap_session.has_capability(gmail::CAPABILITY)?;
+    let mut messages = Vec::with_capacity(uids.len());
+    let label = format!("Listing {:}", imap_session.server_name());
+    let mut progress = Progress::new(label, uids.len(), args.batch_size, args.format);
+    for (batch, batch_set) in batch::batches(uids, args.batch_size) {
+        imap_session.keepalive()?;
+        let mut fetched = fetch_batch(imap_session, batch, &batch_set, args)?;
+        if gmail {
+            add_gmail_attributes(imap_session, &batch_set, &mut fetched)?;
+        }
+        messages.extend(fetched);
+        progress.add(batch.len());
+    }
+    drop(progress);
+    order_messages(&mut messages, args, order.as_deref());
+    Ok(messages)
+}
+
+/// Fetch one batch of messages with the UIDs `batch`, or `batch_set` in the compact notation
+fn fetch_batch(imap_session: &mut ImapSession, batch: &[Uid], batch_set: &str, args: &Args) -> Result<Vec<Message>, ConnectionError> {
+    debug!("Fetching {:} from {:} message(s)", fetch_query(args), batch.len());
+    let fetched = imap_session.uid_fetch(batch_set, fetch_query(args))?;
+    if args.strict_encoding {
+        for envelope in fetched.iter().filter_map(|message| message.envelope()) {
+            message::check_encoding(envelope)?;
+        }
+    }
+    if args.mark_read && !args.dry_run {
+        imap_session.uid_store(batch_set, "+FLAGS.SILENT (\\Seen)")?;
+    }
+
+    if let Some(dir) = &args.save_attachments {
+        for body in fetched.iter().filter_map(|message| message.body()) {
+            for path in attachments::save_attachments(dir, body)? {
+                info!("Saved attachment {:?}", path);
+            }
+        }
+    }
+
+    let messages = fetched.iter()
+        .filter_map(Message::from_fetch)
+        .map(|mut message| {
+            if !args.body {
+                message.body = None;
+            }
+            message
+        })
+        .collect();
+    Ok(messages)
+}
+
+/// Add the Gmail labels and thread IDs of the `messages` with the UIDs `batch_set`, fetched separately
+/// as the imap crate cannot parse them
+fn add_gmail_attributes(imap_session: &mut ImapSession, batch_set: &str, messages: &mut [Message]) -> Result<(), ConnectionError> {
+    let response = imap_session.run_raw_command(&format!("UID FETCH {:} {:}", batch_set, gmail::FETCH_ATTRIBUTES))?;
+    let mut attributes = gmail::parse_fetch_response(&response);
+    for message in messages {
+        if let Some(attributes) = message.uid.and_then(|uid| attributes.remove(&uid)) {
+            message.gmail_labels = Some(attributes.labels);
+            message.gmail_thread_id = attributes.thread_id;
+        }
+    }
+    Ok(())
+}
+
+/// The IMAP search `criteria` restricted to the dates given with `--since` and `--before`
+fn search_criteria(criteria: &[String], gmail: bool, args: &Args) -> Result<String, String> {
+    let mut criteria = if gmail { gmail::search_criteria(criteria) } else { criteria.to_vec() };
+    criteria.extend(search::date_criteria(args.since, args.before));
+    search::search_query(&criteria)
+}
+
+/// List the messages of `folder` matching the IMAP search `criteria`, or the Gmail search query when `gmail` is set
+fn search_messages(server: &Server, folder: &str, criteria: &[String], gmail: bool, args: &Args) -> Result<Vec<Message>, ConnectionError> {
+    let query = search_criteria(criteria, gmail, args).map_err(ConfigError)?;
+    let mut imap_session = ImapSession::connect(server, args.retries, args.write)?;
+    if gmail && !imap_session.has_capability(gmail::CAPABILITY)? {
+        imap_session.logout()?;
+        return Err(ConfigError(format!("\"{:}\" is not a Gmail server, --gmail needs the {:} capability", server.name(), gmail::CAPABILITY)));
+    }
+    imap_session.select(folder)?;
+
+    let mut uids: Vec<Uid> = imap_session.uid_search(&query)?.into_iter().collect();
+    uids.sort_unstable();
+    if let Some(limit) = args.limit {
+        // keep the newest matches
+        uids.drain(..uids.len().saturating_sub(limit as usize));
+    }
+    let messages = if uids.is_empty() { Vec::new() } else { fetch_messages(&mut imap_session, &uids, args)? };
+
+    // be nice to the server and log out
+    imap_session.logout()?;
+
+    Ok(messages)
+}
+
 fn main() {
-    match Settings::load() {
+    let args = Args::from_args();
+    let level = match args.verbose {
+        _ if args.quiet => "error",
+        0 => "warn",
+        1 => "info",
+        _ => "debug",
+    };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level)).init();
+
+    if let Some(Command::Init { force }) = &args.command {
+        let path = match args.config.clone().map_or_else(Settings::default_config_file, Ok) {
+            Ok(path) => path,
+            Err(err) => return exit_with_message(1, err.to_string()),
+        };
+        match Settings::write_default(&path, *force) {
+            Ok(()) => println!("Wrote default configuration to {:?}", path),
+            Err(err) => exit_with_message(1, err.to_string()),
+        }
+        return;
+    }
+
+    if let Some(Command::Schema) = &args.command {
+        println!("{:}", Settings::json_schema());
+        return;
+    }
+
+    match Settings::load_from(args.config.clone()) {
         Err(err) => {
             if let Some(internal_err) = Settings::print_default().err() {
-                eprintln!("ERR: {:?}", internal_err);
+                error!("{:}", internal_err);
             }
             exit_with_message(1, err.to_string())
         },
-        Ok(settings) => {
-            for server in settings.servers() {
-                match list_inbox(&server) {
-                    Ok(_) => println!("---\nDone."),
-                    Err(ImapError(No(msg))) => exit_with_message(1, format!("Invalid {:}", msg)),
-                    Err(ImapError(e)) => eprintln!("{:?}", &e),
-                    Err(ConfigError(e)) => eprintln!("CONFIG: {:?}", e),
-                    Err(EncodingError(e)) => eprintln!("Encoding: {:?}", e),
+        Ok(mut settings) => {
+            if let Some(credentials) = &args.login {
+                settings.override_credentials(credentials);
+            }
+            if let Err(problems) = settings.validate() {
+                for problem in &problems {
+                    error!("{:}", problem);
+                }
+                exit_with_message(1, format!("Found {:} problem(s) in the configuration", problems.len()));
+            }
+
+            if args.folders.len() > 1 && !matches!(args.command, None | Some(Command::List)) {
+                exit_with_message(1, "Multiple folders are only supported when listing messages".to_string());
+            }
+
+            if let Some(Command::Search { criteria, gmail }) = &args.command {
+                if let Err(err) = search_criteria(criteria, *gmail, &args) {
+                    exit_with_message(1, err);
+                }
+            }
+
+            if args.template.is_some() && args.format != Format::Text {
+                exit_with_message(1, "--template only applies to the text format, not to --format table, json or ndjson".to_string());
+            }
+            if args.mark_read && !args.write && !args.dry_run {
+                exit_with_message(1, "Marking messages as read changes them on the server, which needs --write".to_string());
+            }
+
+            // the first Ctrl-C lets every server thread finish its current request and log out
+            let handler = ctrlc::set_handler(|| {
+                if SHUTDOWN.swap(true, Ordering::SeqCst) {
+                    std::process::exit(EXIT_INTERRUPTED);
+                }
+                warn!("Logging out, press Ctrl-C again to quit immediately");
+            });
+            if let Err(err) = handler {
+                warn!("Cannot install Ctrl-C handler: {:}", err);
+            }
+
+            let target = args.command.as_ref().and_then(Command::server_name)
+                .or(args.server.as_deref());
+            if let Some(target) = target {
+                if !settings.servers().any(|server| server.name().eq_ignore_ascii_case(target)) {
+                    let names: Vec<&str> = settings.servers().map(Server::name).collect();
+                    exit_with_message(1, format!("No server named '{:}' configured, available servers: {:}",
+                        target, names.join(", ")));
+                }
+            }
+
+            let servers: Vec<&Server> = settings.servers()
+                .filter(|server| match target {
+                    Some(target) => server.name().eq_ignore_ascii_case(target),
+                    None => server.enabled() || args.all,
+                })
+                .collect();
+            let jobs = args.jobs.unwrap_or(servers.len());
+            let results = run_parallel(&servers, jobs, |server, out| run_command(server, &args, out));
+            let (mut failed, mut rejected) = (0, 0);
+            for (output, result) in &results {
+                if let Err(err) = io::stdout().write_all(output) {
+                    error!("IO error: {:}", err);
+                }
+                match result {
+                    Ok(_) => continue,
+                    Err(AuthError(msg)) => {
+                        error!("{:}", msg);
+                        rejected += 1;
+                    },
+                    Err(ImapError(No(msg))) => error!("Request refused by the server: {:}", msg),
+                    Err(err) => error!("{:}", err),
                 }
+                failed += 1;
+            }
+            if shutdown_requested() {
+                std::process::exit(EXIT_INTERRUPTED);
+            }
+            if results.len() > 1 && !args.quiet {
+                eprintln!("{:} ok, {:} failed", results.len() - failed, failed);
+            }
+            match failed {
+                0 => {},
+                _ if failed < results.len() => process::exit(EXIT_PARTIALLY_FAILED),
+                _ if rejected == failed => process::exit(EXIT_AUTHENTICATION_FAILED),
+                _ => process::exit(EXIT_FAILED),
             }
         }
     }
 }
 
+/// Run the requested command against a single server, writing its output to `out`
+fn run_command(server: &Server, args: &Args, out: &mut dyn Write) -> Result<(), ConnectionError> {
+    match &args.command {
+        None | Some(Command::List) if args.format == Format::Ndjson && !args.thread && server.imap().is_some() => {
+            stream_folders(server, args)?;
+        },
+        None | Some(Command::List) => {
+            let listed = match (server.imap(), server.pop3()) {
+                (None, Some(pop3)) => vec![("", pop3::list_messages(server, pop3, args)?)],
+                _ => list_folders(server, args)?,
+            };
+            match args.format {
+                Format::Text | Format::Table => for (folder, messages) in &listed {
+                    if args.folders.len() > 1 && !args.quiet {
+                        writeln!(out, "=== {:} ===", folder)?;
+                    }
+                    if messages.is_empty() && !args.quiet {
+                        if folder.is_empty() {
+                            writeln!(out, "No messages")?;
+                        } else {
+                            writeln!(out, "No messages in {:}", folder)?;
+                        }
+                    }
+                    render(messages, args.format, &Layout::new(args), out)?;
+                },
+                Format::Json | Format::Ndjson => {
+                    let messages: Vec<Message> = listed.into_iter().flat_map(|(_, messages)| messages).collect();
+                    render(&messages, args.format, &Layout::new(args), out)?;
+                },
+            }
+            if args.format == Format::Text && args.template.is_none() && !args.quiet {
+                writeln!(out, "---\nDone.")?;
+            }
+        },
+        Some(Command::Search { criteria, gmail }) => {
+            let messages = search_messages(server, args.folder(server), criteria, *gmail, args)?;
+            if args.format == Format::Text && !args.quiet {
+                writeln!(out, "Found {:} matching message(s) in {:}", messages.len(), args.folder(server))?;
+            }
+            render(&messages, args.format, &Layout::new(args), out)?;
+            if args.format == Format::Text && args.template.is_none() && !args.quiet {
+                writeln!(out, "---\nDone.")?;
+            }
+        },
+        Some(Command::Flag { folder, uid, seen, unseen, flagged, unflagged }) => {
+            let flag_if = |set: bool, flag: &'static str| Some(flag).filter(|_| set);
+            let add: Vec<&str> = vec![flag_if(*seen, "\\Seen"), flag_if(*flagged, "\\Flagged")]
+                .into_iter().flatten().collect();
+            let remove: Vec<&str> = vec![flag_if(*unseen, "\\Seen"), flag_if(*unflagged, "\\Flagged")]
+                .into_iter().flatten().collect();
+            if add.is_empty() && remove.is_empty() {
+                return Err(ConfigError("No flag operation given, use --seen, --unseen, --flagged or --unflagged".to_string()));
+            }
+            let flags = flag_message(server, folder, *uid, &add, &remove, args)?;
+            let would = if args.dry_run { " would be" } else { "" };
+            writeln!(out, "UID {:} in {:}{:}: {:}", uid, folder, would, flags.join(" "))?;
+        },
+        Some(Command::Move { source, uids, destination }) => {
+            transfer_messages(server, source, uids, destination, true, args)?;
+            let verb = if args.dry_run { "Would move" } else { "Moved" };
+            writeln!(out, "{:} {:} from {:} to {:}", verb, uids, source, destination)?;
+        },
+        Some(Command::Copy { source, uids, destination }) => {
+            transfer_messages(server, source, uids, destination, false, args)?;
+            let verb = if args.dry_run { "Would copy" } else { "Copied" };
+            writeln!(out, "{:} {:} from {:} to {:}", verb, uids, source, destination)?;
+        },
+        Some(Command::Delete { folder, uids, expunge }) => {
+            delete_messages(server, folder, uids, *expunge, args)?;
+            match (*expunge, args.dry_run) {
+                (true, false) => writeln!(out, "Deleted {:} from {:}", uids, folder)?,
+                (true, true) => writeln!(out, "Would delete {:} from {:}", uids, folder)?,
+                (false, false) => writeln!(out, "Flagged {:} in {:} as deleted, expunge the folder to remove them", uids, folder)?,
+                (false, true) => writeln!(out, "Would flag {:} in {:} as deleted", uids, folder)?,
+            }
+        },
+        Some(Command::Export { maildir, mbox }) => {
+            let count = match (maildir, mbox) {
+                (Some(maildir), _) => {
+                    let mut maildir = export::Maildir::create(maildir)?;
+                    export_folder(server, args.folder(server), args, |message, flags, _| maildir.add(message, flags))?
+                },
+                (None, Some(mbox)) => {
+                    let mut mbox = export::Mbox::create(mbox)?;
+                    let count = export_folder(server, args.folder(server), args, |message, _, received| mbox.add(message, received))?;
+                    mbox.flush()?;
+                    count
+                },
+                (None, None) => unreachable!("--maildir or --mbox is required"),
+            };
+            writeln!(out, "Exported {:} message(s) from {:}", count, args.folder(server))?;
+        },
+        Some(Command::Watch { interval }) => {
+            watch_folder(server, args.folder(server), Duration::from_secs(*interval), args)?;
+        },
+        Some(Command::Init { .. }) | Some(Command::Schema) => unreachable!("init and schema are handled before loading the settings"),
+        Some(Command::Quota) => match folder_quota(server, args.folder(server), args.retries)? {
+            None => writeln!(out, "{:}: quotas are not supported by the server", server.name())?,
+            Some(quotas) if quotas.is_empty() => writeln!(out, "{:}: no quota set for {:}", server.name(), args.folder(server))?,
+            Some(quotas) => for quota in quotas {
+                let percent = (quota.usage * 100).checked_div(quota.limit).unwrap_or(0);
+                // STORAGE is counted in units of 1024 octets (RFC 2087)
+                let unit = if quota.resource.eq_ignore_ascii_case("STORAGE") { " KiB" } else { "" };
+                writeln!(out, "{:}/{:}: {:} {:}{:} of {:}{:} used ({:}%)", server.name(), quota.root,
+                    quota.resource, quota.usage, unit, quota.limit, unit, percent)?;
+            },
+        },
+        Some(Command::Capabilities) => for capability in server_capabilities(server, args.retries)? {
+            writeln!(out, "{:}", capability)?;
+        },
+        Some(Command::Folders) => for mailbox in list_mailboxes(server, args.retries)? {
+            writeln!(out, "{:} ({:})", mailbox.name, mailbox.attributes.join(" "))?;
+        },
+        Some(Command::Status) => {
+            let status = folder_status(server, args.folder(server), args.retries)?;
+            writeln!(out, "{:}/{:}: {:} total, {:} recent, {:} unseen",
+                server.name(), args.folder(server), status.total, status.recent,
+                status.unseen.map_or("?".to_string(), |unseen| unseen.to_string()))?;
+        },
+        Some(Command::SetPassword { .. }) => {
+            store_password(server)?;
+            writeln!(out, "Password stored for \"{:}\"", server.name())?;
+        },
+        Some(Command::Send { to, subject, .. }) => {
+            let mut body = String::new();
+            io::stdin().read_to_string(&mut body)?;
+            smtp::send_mail(server, to, subject, &body)?;
+            writeln!(out, "Message sent to {:}", to)?;
+        },
+        Some(Command::Reply { uid, .. }) => {
+            let original = fetch_raw_message(server, args.folder(server), *uid, args.retries)?;
+            let mut reply = Reply::to_message(&original)
+                .ok_or_else(|| ConfigError(format!("Cannot reply to UID {:} in {:}, it has no sender", uid, args.folder(server))))?;
+            let quoted = reply.body;
+            reply.body = reply::edit(&quoted)?;
+            if reply.body == quoted || reply.body.trim().is_empty() {
+                writeln!(out, "Reply left unchanged, not sending it")?;
+            } else {
+                smtp::send_reply(server, &reply)?;
+                writeln!(out, "Reply sent to {:}", reply.to)?;
+            }
+        },
+        Some(Command::Cat { uid, part: None, .. }) => {
+            out.write_all(&fetch_raw_message(server, args.folder(server), *uid, args.retries)?)?;
+        },
+        Some(Command::Cat { uid, part: Some(section), .. }) => {
+            out.write_all(&fetch_part(server, args.folder(server), *uid, section, args.retries)?)?;
+        },
+        Some(Command::Structure { uid, .. }) => for part in fetch_structure(server, args.folder(server), *uid, args.retries)? {
+            writeln!(out, "{:}", part)?;
+        },
+        Some(Command::Append { folder, file, flags, date }) => {
+            let message = match file {
+                Some(file) => fs::read(file)?,
+                None => {
+                    let mut message = Vec::new();
+                    io::stdin().read_to_end(&mut message)?;
+                    message
+                },
+            };
+            check_headers(&message).map_err(ConfigError)?;
+            let flags: Vec<Flag> = flags.iter().map(|flag| Flag::from(flag.as_str())).collect();
+            match append_message(server, folder, &message, &flags, *date, args)? {
+                _ if args.dry_run => writeln!(out, "Would append {:} bytes to {:}", message.len(), folder)?,
+                Some(uid) => writeln!(out, "Appended to {:} as UID {:}", folder, uid)?,
+                None => writeln!(out, "Appended to {:}", folder)?,
+            }
+        },
+        Some(Command::Browse { .. }) => {
+            browse_folder(server, args.folder(server), args, &mut io::stdin().lock(), &mut io::stdout().lock())?;
+        },
+    }
+    Ok(())
+}
+
+/// Captured output and result of running a command against one server
+type ServerOutput = (Vec<u8>, Result<(), ConnectionError>);
+
+/// Run `task` for every server on at most `jobs` threads.
+///
+/// The output of each server is captured separately and returned in the order
+/// of `servers`, so that it can be printed without interleaving.
+fn run_parallel<F>(servers: &[&Server], jobs: usize, task: F) -> Vec<ServerOutput>
+where
+    F: Fn(&Server, &mut dyn Write) -> Result<(), ConnectionError> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let workers = jobs.clamp(1, servers.len().max(1));
+    let mut results: Vec<(usize, ServerOutput)> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| scope.spawn(|| {
+                let mut done = Vec::new();
+                loop {
+                    let index = next.fetch_add(1, Ordering::SeqCst);
+                    let server = match servers.get(index) {
+                        Some(server) if !shutdown_requested() => server,
+                        _ => return done,
+                    };
+                    let mut output = Vec::new();
+                    let result = task(server, &mut output);
+                    done.push((index, (output, result)));
+                }
+            }))
+            .collect();
+        handles.into_iter()
+            .flat_map(|handle| handle.join().expect("Server thread panicked"))
+            .collect()
+    });
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, output)| output).collect()
+}
+
 fn exit_with_message(exit_status: i32, message: String) {
-    eprintln!("ERROR: {:?}", message);
+    error!("{:}", message);
     std::process::exit(exit_status);
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    use super::*;
+
+    /// Serve one connection on a local port like a plaintext IMAP server, answering each command with
+    /// the response of the first `(command, response)` of the `script` it starts with. The last line of
+    /// the response is the tagged status. CAPABILITY reports only IMAP4rev1 unless scripted,
+    /// other commands fail with BAD.
+    fn mock_server(script: &'static [(&'static str, &'static str)]) -> (Server, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            writer.write_all(b"* OK [CAPABILITY IMAP4rev1] Mock server ready\r\n").unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 {
+                let (tag, command) = line.trim_end().split_once(' ').unwrap();
+                let response = script.iter()
+                    .find(|(prefix, _)| command.starts_with(prefix))
+                    .map_or(match command {
+                        "CAPABILITY" => "* CAPABILITY IMAP4rev1\r\nOK CAPABILITY completed",
+                        _ => "BAD Unknown command",
+                    }, |(_, response)| response);
+                let (untagged, status) = response.rsplit_once("\r\n").unwrap_or(("", response));
+                if !untagged.is_empty() {
+                    write!(writer, "{:}\r\n", untagged).unwrap();
+                }
+                write!(writer, "{:} {:}\r\n", tag, status).unwrap();
+                if command == "LOGOUT" {
+                    break;
+                }
+                line.clear();
+            }
+        });
+        let server = toml::from_str(&format!(r#"
+            name = "mock"
+            credentials = {{ username = "alice", password = "secret" }}
+
+            [imap]
+            host = "127.0.0.1"
+            port = {:}
+            allow_insecure = true
+        "#, port)).unwrap();
+        (server, handle)
+    }
+
+    const LOGIN: (&str, &str) = ("LOGIN \"alice\" \"secret\"", "OK LOGIN completed");
+    const LOGOUT: (&str, &str) = ("LOGOUT", "* BYE Logging out\r\nOK LOGOUT completed");
+
+    #[test]
+    fn envelopes_are_listed_from_the_server() {
+        static SCRIPT: [(&str, &str); 5] = [
+            LOGIN,
+            ("SELECT \"INBOX\"", "* 2 EXISTS\r\n* 0 RECENT\r\n* FLAGS (\\Seen)\r\nOK [READ-WRITE] SELECT completed"),
+            ("UID SEARCH 1:2", "* SEARCH 7 9\r\nOK SEARCH completed"),
+            ("UID FETCH 7,9 ALL", "* 1 FETCH (UID 7 FLAGS (\\Seen) INTERNALDATE \"07-Feb-1994 21:52:25 -0800\" RFC822.SIZE 1234 \
+                ENVELOPE (\"Mon, 7 Feb 1994 21:52:25 -0800\" \"Hello\" ((\"Alice\" NIL \"alice\" \"example.com\")) \
+                NIL NIL ((NIL NIL \"bob\" \"example.org\")) NIL NIL NIL \"<1@example.com>\"))\r\n\
+                * 2 FETCH (UID 9 FLAGS () ENVELOPE (NIL \"Re: Hello\" NIL NIL NIL NIL NIL NIL NIL NIL))\r\n\
+                OK FETCH completed"),
+            LOGOUT,
+        ];
+        let (server, handle) = mock_server(&SCRIPT);
+        let args = Args::from_iter(&["postkast"]);
+
+        let mut imap_session = ImapSession::connect(&server, 0, false).unwrap();
+        let messages = list_folder(&mut imap_session, "INBOX", &args).unwrap();
+        imap_session.logout().unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(messages.len(), 2);
+        let hello = &messages[0];
+        assert_eq!(hello.uid, Some(7));
+        assert_eq!(hello.subject.as_deref(), Some("Hello"));
+        assert_eq!(hello.size, Some(1234));
+        assert_eq!(hello.flags, Some(vec!["\\Seen".to_string()]));
+        assert_eq!(hello.message_id.as_deref(), Some("<1@example.com>"));
+        let from = hello.from.as_ref().unwrap();
+        assert_eq!(from[0].name.as_deref(), Some("Alice"));
+        assert_eq!(from[0].mailbox.as_deref(), Some("alice"));
+        assert_eq!(from[0].host.as_deref(), Some("example.com"));
+        assert_eq!(hello.to.as_ref().unwrap()[0].mailbox.as_deref(), Some("bob"));
+        assert_eq!(messages[1].uid, Some(9));
+        assert_eq!(messages[1].subject.as_deref(), Some("Re: Hello"));
+        assert!(messages.iter().all(|message| message.folder.as_deref() == Some("INBOX")));
+    }
+
+    #[test]
+    fn gmail_labels_and_threads_are_listed_from_a_gmail_server() {
+        static SCRIPT: [(&str, &str); 7] = [
+            LOGIN,
+            ("SELECT \"INBOX\"", "* 1 EXISTS\r\n* 0 RECENT\r\nOK [READ-WRITE] SELECT completed"),
+            ("UID SEARCH 1:1", "* SEARCH 7\r\nOK SEARCH completed"),
+            ("CAPABILITY", "* CAPABILITY IMAP4rev1 X-GM-EXT-1\r\nOK CAPABILITY completed"),
+            ("UID FETCH 7 ALL", "* 1 FETCH (UID 7 FLAGS () ENVELOPE (NIL \"Hello\" NIL NIL NIL NIL NIL NIL NIL NIL))\r\nOK FETCH completed"),
+            ("UID FETCH 7 (X-GM-THRID X-GM-LABELS)",
+                "* 1 FETCH (X-GM-THRID 1278455344230334865 X-GM-LABELS (\\Inbox \"Work\") UID 7)\r\nOK Success"),
+            LOGOUT,
+        ];
+        let (server, handle) = mock_server(&SCRIPT);
+        let args = Args::from_iter(&["postkast"]);
+
+        let mut imap_session = ImapSession::connect(&server, 0, false).unwrap();
+        let messages = list_folder(&mut imap_session, "INBOX", &args).unwrap();
+        imap_session.logout().unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].gmail_thread_id, Some(1278455344230334865));
+        assert_eq!(messages[0].gmail_labels, Some(vec!["\\Inbox".to_string(), "Work".to_string()]));
+    }
+
+    #[test]
+    fn browsing_prints_the_messages_picked_by_number() {
+        static SCRIPT: [(&str, &str); 7] = [
+            LOGIN,
+            ("SELECT \"INBOX\"", "* 2 EXISTS\r\n* 0 RECENT\r\nOK [READ-WRITE] SELECT completed"),
+            ("UID SEARCH 1:2", "* SEARCH 7 9\r\nOK SEARCH completed"),
+            ("UID FETCH 7,9 ALL", "* 1 FETCH (UID 7 FLAGS () ENVELOPE (NIL \"Hello\" ((\"Alice\" NIL \"alice\" \"example.com\")) \
+                NIL NIL NIL NIL NIL NIL NIL))\r\n\
+                * 2 FETCH (UID 9 FLAGS () ENVELOPE (NIL \"Re: Hello\" NIL NIL NIL NIL NIL NIL NIL NIL))\r\n\
+                OK FETCH completed"),
+            ("NOOP", "OK NOOP completed"),
+            ("UID FETCH 7 BODY.PEEK[]", "* 1 FETCH (UID 7 BODY[] {22}\r\nSubject: Hello\r\n\r\nHi\r\n)\r\nOK FETCH completed"),
+            LOGOUT,
+        ];
+        let (server, handle) = mock_server(&SCRIPT);
+        let args = Args::from_iter(&["postkast", "browse", "mock"]);
+
+        let mut out = Vec::new();
+        browse_folder(&server, "INBOX", &args, &mut "l\n3\n1\n".as_bytes(), &mut out).unwrap();
+        handle.join().unwrap();
+
+        let listing = "   1  Hello  (Alice <alice@example.com>)\n   2  Re: Hello  ()\n";
+        let prompt = "Message number, 'l' to list again or Ctrl-D to quit: ";
+        assert_eq!(String::from_utf8(out).unwrap(), [
+            listing, prompt,
+            listing, prompt,
+            "No message number '3', expected 1 to 2\n", prompt,
+            "---\nUID: 7\nSubject: Hello\n\nHi\r\n\n", prompt,
+            "\n",
+        ].concat());
+    }
+
+    #[test]
+    fn quiet_listing_has_no_folder_headings() {
+        static SCRIPT: [(&str, &str); 4] = [
+            LOGIN,
+            ("SELECT \"INBOX\"", "* 0 EXISTS\r\n* 0 RECENT\r\nOK [READ-WRITE] SELECT completed"),
+            ("SELECT \"Archive\"", "* 0 EXISTS\r\n* 0 RECENT\r\nOK [READ-WRITE] SELECT completed"),
+            LOGOUT,
+        ];
+        let (server, handle) = mock_server(&SCRIPT);
+        let args = Args::from_iter(&["postkast", "--quiet", "--folder", "INBOX,Archive"]);
+
+        let mut out = Vec::new();
+        run_command(&server, &args, &mut out).unwrap();
+        handle.join().unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "");
+    }
+
+    #[test]
+    fn listing_goes_on_after_the_server_sorted_the_messages() {
+        static SCRIPT: [(&str, &str); 7] = [
+            LOGIN,
+            ("SELECT \"INBOX\"", "* 2 EXISTS\r\n* 0 RECENT\r\nOK [READ-WRITE] SELECT completed"),
+            ("UID SEARCH 1:2", "* SEARCH 7 9\r\nOK SEARCH completed"),
+            ("CAPABILITY", "* CAPABILITY IMAP4rev1 SORT\r\nOK CAPABILITY completed"),
+            // the imap crate cannot parse SORT responses
+            ("UID SORT (DATE) UTF-8 UID 7,9", "* SORT 9 7\r\nOK SORT completed"),
+            ("UID FETCH 7,9 ALL", "* 1 FETCH (UID 7 ENVELOPE (NIL \"Older\" NIL NIL NIL NIL NIL NIL NIL NIL))\r\n\
+                * 2 FETCH (UID 9 ENVELOPE (NIL \"Newer\" NIL NIL NIL NIL NIL NIL NIL NIL))\r\n\
+                OK FETCH completed"),
+            LOGOUT,
+        ];
+        let (server, handle) = mock_server(&SCRIPT);
+        let args = Args::from_iter(&["postkast", "--sort", "date"]);
+
+        let mut imap_session = ImapSession::connect(&server, 0, false).unwrap();
+        let messages = list_folder(&mut imap_session, "INBOX", &args).unwrap();
+        imap_session.logout().unwrap();
+        handle.join().unwrap();
+
+        let uids: Vec<Option<Uid>> = messages.iter().map(|message| message.uid).collect();
+        assert_eq!(uids, vec![Some(9), Some(7)]);
+    }
+
+    #[test]
+    fn sessions_do_not_change_messages_unless_writable() {
+        static SCRIPT: [(&str, &str); 2] = [LOGIN, LOGOUT];
+        let (server, handle) = mock_server(&SCRIPT);
+
+        let mut imap_session = ImapSession::connect(&server, 0, false).unwrap();
+        let err = imap_session.uid_store("7", "+FLAGS (\\Seen)").err().unwrap();
+        let fetch_err = imap_session.uid_fetch("7", "BODY[]").err().unwrap();
+        imap_session.logout().unwrap();
+        handle.join().unwrap();
+
+        // the commands are refused without being sent, the mock server would answer them with BAD
+        assert!(matches!(&err, ConfigError(msg) if msg.ends_with("needs --write")), "unexpected error {:?}", err);
+        assert!(matches!(&fetch_err, ConfigError(_)), "unexpected error {:?}", fetch_err);
+    }
+
+    #[test]
+    fn moving_without_uidplus_keeps_other_deleted_messages() {
+        static SCRIPT: [(&str, &str); 7] = [
+            LOGIN,
+            ("LIST \"\" Archive", "* LIST () \"/\" Archive\r\nOK LIST completed"),
+            ("SELECT \"INBOX\"", "* 9 EXISTS\r\n* 0 RECENT\r\nOK [READ-WRITE] SELECT completed"),
+            ("CAPABILITY", "* CAPABILITY IMAP4rev1\r\nOK CAPABILITY completed"),
+            ("UID SEARCH UID 7", "* SEARCH 7\r\nOK SEARCH completed"),
+            ("UID SEARCH DELETED", "* SEARCH 3 7\r\nOK SEARCH completed"),
+            LOGOUT,
+        ];
+        let (server, handle) = mock_server(&SCRIPT);
+        let args = Args::from_iter(&["postkast", "--write"]);
+
+        // the messages are not even copied, the mock server would answer COPY with BAD
+        let err = transfer_messages(&server, "INBOX", "7", "Archive", true, &args).err().unwrap();
+        handle.join().unwrap();
+
+        assert!(matches!(&err, ConfigError(msg) if msg.contains("1 other message(s)")), "unexpected error {:?}", err);
+    }
+
+    #[test]
+    fn expunging_without_uidplus_keeps_other_deleted_messages() {
+        static SCRIPT: [(&str, &str); 6] = [
+            LOGIN,
+            ("SELECT \"INBOX\"", "* 9 EXISTS\r\n* 0 RECENT\r\nOK [READ-WRITE] SELECT completed"),
+            ("CAPABILITY", "* CAPABILITY IMAP4rev1\r\nOK CAPABILITY completed"),
+            ("UID SEARCH UID 7", "* SEARCH 7\r\nOK SEARCH completed"),
+            ("UID SEARCH DELETED", "* SEARCH 3\r\nOK SEARCH completed"),
+            LOGOUT,
+        ];
+        let (server, handle) = mock_server(&SCRIPT);
+        let args = Args::from_iter(&["postkast", "--write"]);
+
+        // no STORE or EXPUNGE is sent, the mock server would answer it with BAD
+        let err = delete_messages(&server, "INBOX", "7", true, &args).err().unwrap();
+        handle.join().unwrap();
+
+        assert!(matches!(&err, ConfigError(msg) if msg.contains("1 other message(s)")), "unexpected error {:?}", err);
+    }
+
+    #[test]
+    fn messages_are_expunged_without_uidplus_when_no_others_are_deleted() {
+        static SCRIPT: [(&str, &str); 8] = [
+            LOGIN,
+            ("SELECT \"INBOX\"", "* 9 EXISTS\r\n* 0 RECENT\r\nOK [READ-WRITE] SELECT completed"),
+            ("CAPABILITY", "* CAPABILITY IMAP4rev1\r\nOK CAPABILITY completed"),
+            ("UID SEARCH UID 7", "* SEARCH 7\r\nOK SEARCH completed"),
+            ("UID SEARCH DELETED", "* SEARCH 7\r\nOK SEARCH completed"),
+            ("UID STORE 7 +FLAGS.SILENT (\\Deleted)", "OK STORE completed"),
+            ("EXPUNGE", "* 5 EXPUNGE\r\nOK EXPUNGE completed"),
+            LOGOUT,
+        ];
+        let (server, handle) = mock_server(&SCRIPT);
+        let args = Args::from_iter(&["postkast", "--write"]);
+
+        delete_messages(&server, "INBOX", "7", true, &args).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn quota_is_reported_before_logging_out() {
+        static SCRIPT: [(&str, &str); 4] = [
+            LOGIN,
+            ("CAPABILITY", "* CAPABILITY IMAP4rev1 QUOTA\r\nOK CAPABILITY completed"),
+            ("GETQUOTAROOT \"INBOX\"", "* QUOTAROOT INBOX \"\"\r\n* QUOTA \"\" (STORAGE 10 512 MESSAGE 3 1000)\r\n\
+                OK GETQUOTAROOT completed"),
+            LOGOUT,
+        ];
+        let (server, handle) = mock_server(&SCRIPT);
+
+        let quotas = folder_quota(&server, "INBOX", 0).unwrap().unwrap();
+        handle.join().unwrap();
+
+        let quotas: Vec<(&str, &str, u64, u64)> = quotas.iter()
+            .map(|quota| (quota.root.as_str(), quota.resource.as_str(), quota.usage, quota.limit))
+            .collect();
+        assert_eq!(quotas, vec![("", "STORAGE", 10, 512), ("", "MESSAGE", 3, 1000)]);
+    }
+
+    #[test]
+    fn messages_are_threaded_by_the_server() {
+        static SCRIPT: [(&str, &str); 7] = [
+            LOGIN,
+            ("SELECT \"INBOX\"", "* 2 EXISTS\r\n* 0 RECENT\r\nOK [READ-WRITE] SELECT completed"),
+            ("UID SEARCH 1:2", "* SEARCH 7 9\r\nOK SEARCH completed"),
+            ("CAPABILITY", "* CAPABILITY IMAP4rev1 THREAD=REFERENCES\r\nOK CAPABILITY completed"),
+            ("UID FETCH 7,9 (FLAGS INTERNALDATE RFC822.SIZE ENVELOPE BODY.PEEK[HEADER.FIELDS (REFERENCES)])",
+                "* 1 FETCH (UID 7 ENVELOPE (NIL \"Hello\" NIL NIL NIL NIL NIL NIL NIL NIL))\r\n\
+                * 2 FETCH (UID 9 ENVELOPE (NIL \"Re: Hello\" NIL NIL NIL NIL NIL NIL NIL NIL))\r\n\
+                OK FETCH completed"),
+            // the imap crate cannot parse THREAD responses
+            ("UID THREAD REFERENCES UTF-8 UID 7,9", "* THREAD (7 9)\r\nOK THREAD completed"),
+            LOGOUT,
+        ];
+        let (server, handle) = mock_server(&SCRIPT);
+        let args = Args::from_iter(&["postkast", "--thread"]);
+
+        let mut imap_session = ImapSession::connect(&server, 0, false).unwrap();
+        let messages = list_folder(&mut imap_session, "INBOX", &args).unwrap();
+        imap_session.logout().unwrap();
+        handle.join().unwrap();
+
+        let threaded: Vec<(Option<Uid>, Option<usize>)> = messages.iter().map(|message| (message.uid, message.thread_depth)).collect();
+        assert_eq!(threaded, vec![(Some(7), Some(0)), (Some(9), Some(1))]);
+    }
+
+    #[test]
+    fn pooled_session_is_reused_and_logged_out() {
+        static SCRIPT: [(&str, &str); 4] = [
+            LOGIN,
+            ("SELECT \"INBOX\"", "* 0 EXISTS\r\n* 0 RECENT\r\nOK [READ-WRITE] SELECT completed"),
+            ("NOOP", "OK NOOP completed"),
+            LOGOUT,
+        ];
+        let (server, handle) = mock_server(&SCRIPT);
+
+        let mut pool = SessionPool::new(0);
+        pool.session(&server).unwrap().select("INBOX").unwrap();
+        // the mock server accepts a single connection, so logging in again would fail
+        assert_eq!(pool.session(&server).unwrap().selected_folder(), Some("INBOX"));
+        drop(pool);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn rejected_login_fails_to_connect() {
+        static SCRIPT: [(&str, &str); 1] = [
+            ("LOGIN", "NO [AUTHENTICATIONFAILED] Invalid credentials"),
+        ];
+        let (server, handle) = mock_server(&SCRIPT);
+
+        let err = ImapSession::connect(&server, 0, false).err().unwrap();
+        handle.join().unwrap();
+
+        assert!(matches!(err, AuthError(_)), "unexpected error {:?}", err);
+    }
+
+    #[test]
+    fn empty_mailbox_is_listed_without_fetching() {
+        static SCRIPT: [(&str, &str); 3] = [
+            LOGIN,
+            ("SELECT \"INBOX\"", "* 0 EXISTS\r\n* 0 RECENT\r\nOK [READ-WRITE] SELECT completed"),
+            LOGOUT,
+        ];
+        let (server, handle) = mock_server(&SCRIPT);
+        let args = Args::from_iter(&["postkast"]);
+
+        let mut imap_session = ImapSession::connect(&server, 0, false).unwrap();
+        let messages = list_folder(&mut imap_session, "INBOX", &args).unwrap();
+        imap_session.logout().unwrap();
+        handle.join().unwrap();
+
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn rejected_login_is_an_authentication_error() {
+        match login_error("GMail", No("[AUTHENTICATIONFAILED] Invalid credentials".to_string())) {
+            AuthError(msg) => assert_eq!(msg, "Invalid credentials for GMail: [AUTHENTICATIONFAILED] Invalid credentials"),
+            _ => panic!("expected an authentication error"),
+        }
+    }
+
+    #[test]
+    fn empty_mailbox_has_nothing_to_fetch() {
+        let args = Args::from_iter(&["postkast", "--limit", "100"]);
+        assert_eq!(mailbox_range(&Mailbox::default(), &args), None);
+
+        let mailbox = Mailbox { exists: 250, ..Mailbox::default() };
+        assert_eq!(mailbox_range(&mailbox, &args), Some("151:250".to_string()));
+    }
+
+    #[test]
+    fn invalid_utf8_envelope_is_an_encoding_error() {
+        fn check(envelope: &imap_proto::types::Envelope) -> Result<(), ConnectionError> {
+            Ok(message::check_encoding(envelope)?)
+        }
+        let mut envelope = imap_proto::types::Envelope {
+            date: Some(b"Mon, 7 Feb 1994 21:52:25 -0800"),
+            subject: Some("Grüße".as_bytes()),
+            from: Some(vec![imap_proto::types::Address {
+                name: Some(b"Alice"),
+                adl: None,
+                mailbox: Some(b"alice"),
+                host: Some(b"example.com"),
+            }]),
+            sender: None,
+            reply_to: None,
+            to: None,
+            cc: None,
+            bcc: None,
+            in_reply_to: None,
+            message_id: Some(b"<1@example.com>"),
+        };
+        assert!(check(&envelope).is_ok());
+
+        // "Grüße" in Latin-1
+        envelope.subject = Some(b"Gr\xfc\xdfe");
+        assert!(matches!(check(&envelope), Err(EncodingError(_))));
+    }
+
+    #[test]
+    fn other_login_failures_are_imap_errors() {
+        assert!(matches!(login_error("GMail", Bad("syntax error".to_string())), ImapError(Bad(_))));
+        assert!(matches!(login_error("GMail", ConnectionLost), ImapError(ConnectionLost)));
+    }
+
+    #[test]
+    fn appended_messages_need_a_from_header() {
+        assert!(check_headers(b"From: alice@example.com\r\nSubject: Hi\r\n\r\nHello\r\n").is_ok());
+        assert!(check_headers(b"Subject: Hi\r\n\r\nHello\r\n").is_err());
+    }
+}
@@ -0,0 +1,329 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::{from_utf8, Utf8Error};
+
+use chrono::{DateTime, FixedOffset};
+use imap::types::{Fetch, Uid};
+use imap_proto::types::Envelope;
+use mailparse::{MailAddr, MailHeaderMap, SingleInfo};
+use serde::Serialize;
+
+use crate::args::SortKey;
+use crate::mime;
+
+/// Email address of a message sender or recipient
+#[derive(Debug, Serialize)]
+pub struct Address {
+    pub name: Option<String>,
+    pub mailbox: Option<String>,
+    pub host: Option<String>,
+}
+
+/// Message summary built from the IMAP envelope or the message headers
+#[derive(Debug, Serialize)]
+pub struct Message {
+    /// Folder the message was listed from, unknown for POP3 messages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub folder: Option<String>,
+    pub uid: Option<Uid>,
+    pub from: Option<Vec<Address>>,
+    /// Address of the agent that sent the message on behalf of the From addresses
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sender: Option<Vec<Address>>,
+    /// Addresses replies should go to instead of the From addresses
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<Vec<Address>>,
+    pub to: Option<Vec<Address>>,
+    pub cc: Option<Vec<Address>>,
+    pub bcc: Option<Vec<Address>>,
+    pub date: Option<DateTime<FixedOffset>>,
+    /// Date as given by the server, for showing dates that could not be parsed
+    #[serde(skip)]
+    pub raw_date: Option<String>,
+    pub subject: Option<String>,
+    /// Time the server received the message, which can differ from its Date header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub internal_date: Option<DateTime<FixedOffset>>,
+    /// Size of the whole message in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<String>,
+    /// Message IDs of the `References` header, from the thread root to the direct parent
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub references: Vec<String>,
+    /// Reply depth within its conversation thread, when listing threads
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread_depth: Option<usize>,
+    /// IMAP system flags like `\Seen` and custom keywords, unknown for POP3 messages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flags: Option<Vec<String>>,
+    /// Gmail labels, only known on Gmail servers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gmail_labels: Option<Vec<String>>,
+    /// Gmail conversation thread ID, only known on Gmail servers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gmail_thread_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+}
+
+/// Display wrapper rendering an address as `Name <mailbox@host>`,
+/// or just `mailbox@host` when the address has no name
+pub struct DisplayAddress<'a>(pub &'a Address);
+
+/// Display wrapper rendering a list of envelope addresses separated with commas.
+///
+/// The envelope marks the start of a group with an address without a host, whose mailbox
+/// is the group name, and the end of the group with an address without mailbox and host.
+/// Groups are rendered as `Group: member@host, other@host;`.
+pub struct DisplayAddresses<'a>(pub &'a [Address]);
+
+impl fmt::Display for DisplayAddress<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mailbox = self.0.mailbox.as_deref().unwrap_or_default();
+        let address = match &self.0.host {
+            Some(host) => format!("{:}@{:}", mailbox, host),
+            None => mailbox.to_string(),
+        };
+        match self.0.name.as_deref().filter(|name| !name.is_empty()) {
+            Some(name) => write!(f, "{} <{}>", quote_name(name), address),
+            None => f.write_str(&address),
+        }
+    }
+}
+
+impl fmt::Display for DisplayAddresses<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut separator = "";
+        for address in self.0 {
+            match (&address.mailbox, &address.host) {
+                (Some(group), None) => {
+                    write!(f, "{:}{:}:", separator, group)?;
+                    separator = " ";
+                },
+                (None, None) => {
+                    f.write_str(";")?;
+                    separator = ", ";
+                },
+                _ => {
+                    write!(f, "{:}{:}", separator, DisplayAddress(address))?;
+                    separator = ", ";
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Display name, quoted when it contains characters that are special in an address (RFC 5322)
+fn quote_name(name: &str) -> String {
+    if name.chars().any(|c| "()<>[]:;@\\,.\"".contains(c)) {
+        format!("\"{:}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        name.to_string()
+    }
+}
+
+impl Address {
+    fn from_imap(address: &imap_proto::types::Address) -> Self {
+        let decode_to_string = |bytes| from_utf8(bytes).ok().map(str::to_string);
+        Address {
+            name: address.name.map(mime::decode_header),
+            mailbox: address.mailbox.and_then(decode_to_string),
+            host: address.host.and_then(decode_to_string),
+        }
+    }
+}
+
+impl Address {
+    fn from_mail_addr(address: &MailAddr) -> Vec<Self> {
+        let from_single = |info: &SingleInfo| {
+            let (mailbox, host) = match info.addr.rsplit_once('@') {
+                Some((mailbox, host)) => (Some(mailbox.to_string()), Some(host.to_string())),
+                None => (Some(info.addr.clone()), None),
+            };
+            Address { name: info.display_name.clone(), mailbox, host }
+        };
+        match address {
+            MailAddr::Single(info) => vec![from_single(info)],
+            MailAddr::Group(group) => group.addrs.iter().map(from_single).collect(),
+        }
+    }
+}
+
+impl Message {
+    /// Build the message summary from the headers of a raw RFC 822 message,
+    /// including its text body if `with_body` is set
+    pub fn from_rfc822(message: &[u8], with_body: bool) -> Option<Self> {
+        let (headers, _) = mailparse::parse_headers(message).ok()?;
+        let addresses = |key: &str| headers.get_first_value(key)
+            .and_then(|value| mailparse::addrparse(&value).ok())
+            .map(|list| list.iter().flat_map(Address::from_mail_addr).collect::<Vec<_>>());
+        Some(Message {
+            folder: None,
+            uid: None,
+            from: addresses("From"),
+            sender: addresses("Sender"),
+            reply_to: addresses("Reply-To"),
+            to: addresses("To"),
+            cc: addresses("Cc"),
+            bcc: addresses("Bcc"),
+            date: headers.get_first_header("Date").and_then(|date| mime::parse_date(date.get_value_raw())),
+            raw_date: headers.get_first_value("Date"),
+            subject: headers.get_first_value("Subject"),
+            internal_date: None,
+            size: None,
+            message_id: headers.get_first_value("Message-ID").map(|id| id.trim().to_string()),
+            in_reply_to: headers.get_first_value("In-Reply-To").map(|id| id.trim().to_string()),
+            references: message_ids(headers.get_first_value("References")),
+            thread_depth: None,
+            flags: None,
+            gmail_labels: None,
+            gmail_thread_id: None,
+            body: Some(message).filter(|_| with_body).and_then(mime::text_body),
+        })
+    }
+
+    /// Build the message summary from the envelope of a fetch result,
+    /// or from its header fields when only those were fetched
+    pub fn from_fetch(fetch: &Fetch) -> Option<Self> {
+        let mut message = match (fetch.envelope(), fetch.header()) {
+            (Some(envelope), _) => Message::from_envelope(envelope),
+            (None, Some(header)) => Message::from_rfc822(header, false)?,
+            (None, None) => return None,
+        };
+        // the envelope has no references, those come from the fetched header fields or message
+        if let Some((headers, _)) = fetch.header().or_else(|| fetch.body())
+            .and_then(|header| mailparse::parse_headers(header).ok())
+        {
+            message.references = message_ids(headers.get_first_value("References"));
+        }
+        message.uid = fetch.uid;
+        message.internal_date = fetch.internal_date();
+        message.size = fetch.size;
+        message.flags = Some(fetch.flags().iter().map(|flag| flag.to_string()).collect());
+        message.body = fetch.body().and_then(mime::text_body);
+        Some(message)
+    }
+
+    /// Whether the `addresses` are the same as the From addresses, apart from their names
+    pub fn is_from(&self, addresses: &[Address]) -> bool {
+        let key = |address: &Address| (address.mailbox.as_deref().map(str::to_lowercase), address.host.as_deref().map(str::to_lowercase));
+        match &self.from {
+            Some(from) => from.len() == addresses.len() && from.iter().map(key).eq(addresses.iter().map(key)),
+            None => false,
+        }
+    }
+
+    /// Values to sort the message by, sorting senders by their mailbox name
+    pub fn sort_values(&self) -> SortValues {
+        SortValues {
+            date: self.date,
+            arrival: self.internal_date,
+            size: self.size,
+            subject: self.subject.clone(),
+            from: self.from.as_ref()
+                .and_then(|from| from.first())
+                .and_then(|address| address.mailbox.clone()),
+        }
+    }
+
+    fn from_envelope(envelope: &Envelope) -> Self {
+        let addresses = |list: &Option<Vec<imap_proto::types::Address>>| {
+            list.as_ref().map(|list| list.iter().map(Address::from_imap).collect::<Vec<_>>())
+        };
+        let decode_to_string = |bytes| from_utf8(bytes).ok().map(|id| id.trim().to_string());
+        Message {
+            folder: None,
+            uid: None,
+            from: addresses(&envelope.from),
+            sender: addresses(&envelope.sender),
+            reply_to: addresses(&envelope.reply_to),
+            to: addresses(&envelope.to),
+            cc: addresses(&envelope.cc),
+            bcc: addresses(&envelope.bcc),
+            date: envelope.date.and_then(mime::parse_date),
+            raw_date: envelope.date.and_then(|date| from_utf8(date).ok()).map(str::to_string),
+            subject: envelope.subject.map(mime::decode_header),
+            internal_date: None,
+            size: None,
+            message_id: envelope.message_id.and_then(decode_to_string),
+            in_reply_to: envelope.in_reply_to.and_then(decode_to_string),
+            references: Vec::new(),
+            thread_depth: None,
+            flags: None,
+            gmail_labels: None,
+            gmail_thread_id: None,
+            body: None,
+        }
+    }
+}
+
+/// Check that the raw text of the `envelope` is valid UTF-8. Listing messages otherwise
+/// decodes it leniently, replacing invalid text or leaving it out.
+pub fn check_encoding(envelope: &Envelope) -> Result<(), Utf8Error> {
+    let lists = [&envelope.from, &envelope.sender, &envelope.reply_to, &envelope.to, &envelope.cc, &envelope.bcc];
+    let addresses = lists.iter()
+        .flat_map(|list| list.iter().flatten())
+        .flat_map(|address| vec![address.name, address.adl, address.mailbox, address.host]);
+    let texts = vec![envelope.date, envelope.subject, envelope.in_reply_to, envelope.message_id];
+    for text in texts.into_iter().chain(addresses).flatten() {
+        from_utf8(text)?;
+    }
+    Ok(())
+}
+
+/// Message IDs like `<id@host>` in a `References` or `In-Reply-To` header value
+fn message_ids(value: Option<String>) -> Vec<String> {
+    value.map_or_else(Vec::new, |value| value.split_whitespace().map(str::to_string).collect())
+}
+
+/// Values of a message that messages can be sorted by
+#[derive(Debug, Default)]
+pub struct SortValues {
+    pub date: Option<DateTime<FixedOffset>>,
+    pub arrival: Option<DateTime<FixedOffset>>,
+    pub size: Option<u32>,
+    pub subject: Option<String>,
+    pub from: Option<String>,
+}
+
+/// Put the `messages` in the requested order, with `values` giving the sort values of a message.
+///
+/// Sorting puts the oldest or smallest messages first, or subjects and senders in alphabetical order,
+/// and the other way around with `reverse`. Messages without the sort value go last
+/// either way, keeping their original order. Without a sort key, `reverse` just
+/// reverses the original order.
+pub fn sort_messages<T, F>(messages: &mut [T], sort: Option<SortKey>, reverse: bool, values: F)
+where
+    F: Fn(&T) -> SortValues,
+{
+    fn compare<V: Ord>(a: Option<V>, b: Option<V>, reverse: bool) -> Ordering {
+        match (a, b) {
+            (Some(a), Some(b)) if reverse => b.cmp(&a),
+            (Some(a), Some(b)) => a.cmp(&b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+    }
+    let lowercase = |value: Option<String>| value.map(|value| value.to_lowercase());
+
+    match sort {
+        Some(key) => messages.sort_by(|a, b| {
+            let (a, b) = (values(a), values(b));
+            match key {
+                SortKey::Date => compare(a.date, b.date, reverse),
+                SortKey::Arrival => compare(a.arrival, b.arrival, reverse),
+                SortKey::Size => compare(a.size, b.size, reverse),
+                SortKey::Subject => compare(lowercase(a.subject), lowercase(b.subject), reverse),
+                SortKey::From => compare(lowercase(a.from), lowercase(b.from), reverse),
+            }
+        }),
+        None if reverse => messages.reverse(),
+        None => {},
+    }
+}
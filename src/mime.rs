@@ -0,0 +1,279 @@
+use std::str::from_utf8;
+
+use chrono::{DateTime, FixedOffset};
+use encoding_rs::Encoding;
+use mailparse::{DispositionType, ParsedMail};
+
+/// Decode a raw header value, replacing any RFC 2047 encoded-words
+/// (`=?charset?encoding?text?=`) with their UTF-8 representation.
+///
+/// Raw bytes that are not valid UTF-8 are replaced with U+FFFD instead of dropping the value.
+pub fn decode_header(bytes: &[u8]) -> String {
+    decode_words(&String::from_utf8_lossy(bytes))
+}
+
+/// Replace all RFC 2047 encoded-words in the `input` with their decoded text.
+///
+/// Whitespace between two adjacent encoded-words is dropped, as required by the RFC.
+/// Encoded-words that fail to decode are left in the output as they are.
+pub fn decode_words(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    let mut after_encoded_word = false;
+
+    while let Some(start) = rest.find("=?") {
+        let (before, candidate) = rest.split_at(start);
+        match decode_encoded_word(candidate) {
+            Some((decoded, consumed)) => {
+                if !(after_encoded_word && before.trim().is_empty()) {
+                    output.push_str(before);
+                }
+                output.push_str(&decoded);
+                rest = &candidate[consumed..];
+                after_encoded_word = true;
+            },
+            None => {
+                output.push_str(before);
+                output.push_str("=?");
+                rest = &candidate[2..];
+                after_encoded_word = false;
+            },
+        }
+    }
+    output.push_str(rest);
+
+    output
+}
+
+/// Decode a single encoded-word at the start of `word`.
+///
+/// Returns the decoded text along with the number of bytes consumed from `word`.
+fn decode_encoded_word(word: &str) -> Option<(String, usize)> {
+    let inner = word.strip_prefix("=?")?;
+    let mut parts = inner.splitn(3, '?');
+    let charset = parts.next()?;
+    let encoding = parts.next()?;
+    let remainder = parts.next()?;
+    let end = remainder.find("?=")?;
+    let text = &remainder[..end];
+
+    if charset.is_empty() || charset.contains(char::is_whitespace) || text.contains(char::is_whitespace) {
+        return None;
+    }
+
+    let bytes = match encoding {
+        "B" | "b" => base64::decode(text).ok()?,
+        "Q" | "q" => decode_q(text)?,
+        _ => return None,
+    };
+
+    let consumed = "=?".len() + charset.len() + 1 + encoding.len() + 1 + end + "?=".len();
+
+    // RFC 2231 allows a language suffix after the charset name (e.g. `UTF-8*en`)
+    let charset = charset.split('*').next()?;
+    Some((decode_charset(charset, &bytes), consumed))
+}
+
+/// Decode the "Q" encoding, a variant of quoted-printable used in headers
+fn decode_q(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut input = text.bytes();
+    while let Some(byte) = input.next() {
+        match byte {
+            b'_' => bytes.push(b' '),
+            b'=' => {
+                let high = (input.next()? as char).to_digit(16)?;
+                let low = (input.next()? as char).to_digit(16)?;
+                bytes.push((high * 16 + low) as u8);
+            },
+            byte => bytes.push(byte),
+        }
+    }
+    Some(bytes)
+}
+
+/// Convert `bytes` in the given `charset` to a UTF-8 string, decoding them as UTF-8
+/// when the charset is unknown. Malformed sequences are replaced with U+FFFD.
+fn decode_charset(charset: &str, bytes: &[u8]) -> String {
+    match Encoding::for_label(charset.as_bytes()) {
+        Some(encoding) => encoding.decode_without_bom_handling(bytes).0.into_owned(),
+        None => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+/// Parse an RFC 2822 date, as found in the `Date:` header and the message envelope.
+///
+/// Tolerates a few common deviations from the standard: single digit days, trailing
+/// comments like `(PDT)` and obsolete time zone names (`GMT`, `UT`, `EST`, ...).
+pub fn parse_date(bytes: &[u8]) -> Option<DateTime<FixedOffset>> {
+    let date = from_utf8(bytes).ok()?;
+    let date = match date.find('(') {
+        Some(comment) => &date[..comment],
+        None => date,
+    };
+
+    let mut tokens: Vec<&str> = date.split_whitespace().collect();
+    if let Some(zone) = tokens.last_mut() {
+        if let Some(offset) = obsolete_zone_offset(zone) {
+            *zone = offset;
+        }
+    }
+
+    let normalized = tokens.iter()
+        .map(|token| match token.len() {
+            1 if token.chars().all(|c| c.is_ascii_digit()) => format!("0{:}", token),
+            _ => token.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join(" ");
+
+    DateTime::parse_from_rfc2822(&normalized).ok()
+}
+
+/// Numeric offset of an obsolete RFC 822 time zone name
+fn obsolete_zone_offset(zone: &str) -> Option<&'static str> {
+    match zone.to_ascii_uppercase().as_str() {
+        "UT" | "GMT" | "Z" => Some("+0000"),
+        "EST" => Some("-0500"),
+        "EDT" => Some("-0400"),
+        "CST" => Some("-0600"),
+        "CDT" => Some("-0500"),
+        "MST" => Some("-0700"),
+        "MDT" => Some("-0600"),
+        "PST" => Some("-0800"),
+        "PDT" => Some("-0700"),
+        // RFC 2822 section 4.3: other alphabetic zones should be treated as "-0000"
+        other if other.chars().all(|c| c.is_ascii_alphabetic()) => Some("-0000"),
+        _ => None,
+    }
+}
+
+/// Extract the readable text of a raw RFC 822 message.
+///
+/// Transfer encoding and charset are decoded. For multipart messages the first
+/// `text/plain` part is used, falling back to the first `text/html` part with the
+/// markup stripped.
+pub fn text_body(message: &[u8]) -> Option<String> {
+    let mail = mailparse::parse_mail(message).ok()?;
+    if let Some(part) = find_part(&mail, "text/plain") {
+        return part.get_body().ok();
+    }
+    find_part(&mail, "text/html")
+        .and_then(|part| part.get_body().ok())
+        .map(|html| strip_tags(&html))
+}
+
+/// File attached to a message
+pub struct Attachment {
+    pub filename: String,
+    pub content: Vec<u8>,
+}
+
+/// Extract all parts of a raw RFC 822 message that carry a file name,
+/// with their transfer encoding decoded.
+pub fn attachments(message: &[u8]) -> Vec<Attachment> {
+    let mut attachments = Vec::new();
+    if let Ok(mail) = mailparse::parse_mail(message) {
+        collect_attachments(&mail, &mut attachments);
+    }
+    attachments
+}
+
+fn collect_attachments(mail: &ParsedMail, attachments: &mut Vec<Attachment>) {
+    let disposition = mail.get_content_disposition();
+    let filename = disposition.params.get("filename").cloned().or_else(|| {
+        match disposition.disposition {
+            DispositionType::Attachment => mail.ctype.params.get("name").cloned(),
+            _ => None,
+        }
+    });
+
+    if let Some(filename) = filename {
+        if let Ok(content) = mail.get_body_raw() {
+            let filename = decode_words(&filename);
+            attachments.push(Attachment { filename, content });
+        }
+    }
+
+    for part in &mail.subparts {
+        collect_attachments(part, attachments);
+    }
+}
+
+/// Find the first inline leaf part with the given MIME type
+fn find_part<'a, 'b>(mail: &'b ParsedMail<'a>, mimetype: &str) -> Option<&'b ParsedMail<'a>> {
+    if mail.subparts.is_empty() {
+        let inline = mail.get_content_disposition().disposition != DispositionType::Attachment;
+        if inline && mail.ctype.mimetype.eq_ignore_ascii_case(mimetype) {
+            Some(mail)
+        } else {
+            None
+        }
+    } else {
+        mail.subparts.iter().find_map(|part| find_part(part, mimetype))
+    }
+}
+
+/// Crude conversion of HTML to plain text: drops tags, scripts and styles,
+/// and decodes the most common character entities.
+fn strip_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        let tag = &rest[start..];
+        let end = tag.find('>').map_or(tag.len(), |end| end + 1);
+        let name = tag[1..end].trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '>')
+            .next()
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        rest = &tag[end..];
+
+        if name == "script" || name == "style" {
+            let closing = format!("</{:}", name);
+            rest = match rest.to_ascii_lowercase().find(&closing) {
+                Some(close) => &rest[close..],
+                None => "",
+            };
+        } else if name == "br" || name == "p" || name == "div" || name == "li" || name == "tr" {
+            text.push('\n');
+        }
+    }
+    text.push_str(rest);
+
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latin1_encoded_words_are_decoded() {
+        assert_eq!(decode_words("=?ISO-8859-1?Q?J=F6rg_M=FCller?="), "Jörg Müller");
+        assert_eq!(decode_words("=?iso-8859-1?B?SvZyZw==?="), "Jörg");
+    }
+
+    #[test]
+    fn shift_jis_encoded_words_are_decoded() {
+        assert_eq!(decode_words("=?Shift_JIS?B?k/qWe4zq?="), "日本語");
+        assert_eq!(decode_words("Re: =?shift_jis?B?k/qWe4zq?= =?UTF-8?Q?_text?="), "Re: 日本語 text");
+    }
+
+    #[test]
+    fn unknown_charset_falls_back_to_lossy_utf8() {
+        assert_eq!(decode_words("=?x-unknown?Q?caf=C3=A9?="), "café");
+        assert_eq!(decode_words("=?x-unknown?Q?caf=E9?="), "caf\u{FFFD}");
+    }
+
+    #[test]
+    fn raw_header_bytes_that_are_not_utf8_are_kept() {
+        assert_eq!(decode_header(b"caf\xe9 =?UTF-8?Q?ol=C3=A9?="), "caf\u{FFFD} olé");
+    }
+}
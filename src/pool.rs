@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+
+use crate::session::ImapSession;
+use crate::settings::Server;
+use crate::ConnectionError;
+
+/// Authenticated sessions kept open by server name, so that repeated commands log in only once.
+/// The sessions only read messages.
+/// The sessions are logged out when the pool is dropped.
+pub struct SessionPool {
+    /// Retries of transient failures when logging in
+    retries: u32,
+    sessions: HashMap<String, ImapSession>,
+}
+
+impl SessionPool {
+    pub fn new(retries: u32) -> Self {
+        SessionPool { retries, sessions: HashMap::new() }
+    }
+
+    /// Session with `server`, reusing the pooled one while it still answers a NOOP. Otherwise
+    /// logs in again, selecting the folder the lost session had selected.
+    pub fn session(&mut self, server: &Server) -> Result<&mut ImapSession, ConnectionError> {
+        let mut folder = None;
+        if let Some(mut session) = self.sessions.remove(server.name()) {
+            match session.noop() {
+                Ok(()) => return Ok(self.sessions.entry(server.name().to_string()).or_insert(session)),
+                Err(err) => {
+                    warn!("Session with \"{:}\" lost, logging in again: {:}", server.name(), err);
+                    folder = session.selected_folder().map(str::to_string);
+                },
+            }
+        }
+
+        let mut session = ImapSession::connect(server, self.retries, false)?;
+        if let Some(folder) = &folder {
+            session.select(folder)?;
+        }
+        Ok(self.sessions.entry(server.name().to_string()).or_insert(session))
+    }
+}
+
+impl Drop for SessionPool {
+    /// Be nice to the servers and log out
+    fn drop(&mut self) {
+        for (name, mut session) in self.sessions.drain() {
+            if let Err(err) = session.logout() {
+                debug!("Cannot log out of \"{:}\": {:}", name, err);
+            }
+        }
+    }
+}
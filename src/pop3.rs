@@ -0,0 +1,223 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+
+use mailparse::MailHeaderMap;
+use native_tls::TlsStream;
+
+use crate::args::Args;
+use crate::message::{sort_messages, Message, SortValues};
+use crate::settings::{Credentials, Pop3, Server};
+use crate::ConnectionError::{self, AuthError, ConfigError, Pop3Error};
+use crate::{command_password, keyring_password, mime, open_stream, threading, tls_connector};
+
+/// Minimal POP3 client session (RFC 1939), enough to list and retrieve messages
+pub struct Pop3Session<S: Read + Write> {
+    stream: BufReader<S>,
+}
+
+impl<S: Read + Write> Pop3Session<S> {
+    /// Start a session on a freshly opened connection by reading the server greeting
+    pub fn new(stream: S) -> Result<Self, ConnectionError> {
+        let mut session = Pop3Session { stream: BufReader::new(stream) };
+        session.read_status()?;
+        Ok(session)
+    }
+
+    /// Authenticate with the USER and PASS commands
+    pub fn login(&mut self, name: &str, username: &str, password: &str) -> Result<(), ConnectionError> {
+        self.command(&format!("USER {:}", username))?;
+        self.command(&format!("PASS {:}", password)).map_err(|err| match err {
+            Pop3Error(msg) => AuthError(format!("Invalid credentials for {:}: {:}", name, msg)),
+            err => err,
+        })?;
+        Ok(())
+    }
+
+    /// Number of messages in the maildrop
+    pub fn stat(&mut self) -> Result<u32, ConnectionError> {
+        let status = self.command("STAT")?;
+        status.split_whitespace().next()
+            .and_then(|count| count.parse().ok())
+            .ok_or_else(|| Pop3Error(format!("Unexpected STAT response: {:}", status)))
+    }
+
+    /// Headers of the message `number`
+    pub fn top(&mut self, number: u32) -> Result<Vec<u8>, ConnectionError> {
+        self.command(&format!("TOP {:} 0", number))?;
+        self.read_multiline()
+    }
+
+    /// The whole message `number`
+    pub fn retr(&mut self, number: u32) -> Result<Vec<u8>, ConnectionError> {
+        self.command(&format!("RETR {:}", number))?;
+        self.read_multiline()
+    }
+
+    /// End the session
+    pub fn quit(&mut self) -> Result<(), ConnectionError> {
+        self.command("QUIT").map(|_| ())
+    }
+
+    fn command(&mut self, command: &str) -> Result<String, ConnectionError> {
+        let stream = self.stream.get_mut();
+        stream.write_all(command.as_bytes())?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()?;
+        self.read_status()
+    }
+
+    /// Read a `+OK` or `-ERR` status line, returning the rest of a `+OK` line
+    fn read_status(&mut self) -> Result<String, ConnectionError> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line)? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let line = line.trim_end();
+        if let Some(rest) = line.strip_prefix("+OK") {
+            Ok(rest.trim_start().to_string())
+        } else {
+            Err(Pop3Error(line.strip_prefix("-ERR").unwrap_or(line).trim_start().to_string()))
+        }
+    }
+
+    /// Read a multi-line response up to the terminating `.` line, undoing the dot-stuffing
+    fn read_multiline(&mut self) -> Result<Vec<u8>, ConnectionError> {
+        let mut data = Vec::new();
+        loop {
+            let mut line = Vec::new();
+            if self.stream.read_until(b'\n', &mut line)? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            if line == b".\r\n" || line == b".\n" {
+                return Ok(data);
+            }
+            let line = if line.starts_with(b"..") { &line[1..] } else { &line[..] };
+            data.extend_from_slice(line);
+        }
+    }
+}
+
+/// Connect and log in to the POP3 server of `server`
+fn connect(server: &Server, pop3: &Pop3) -> Result<Pop3Session<TlsStream<TcpStream>>, ConnectionError> {
+    let name = server.name();
+    info!("Connecting to server \"{:}\" at {:}:{:}", name, pop3.host(), pop3.port());
+
+    if pop3.tls().is_none() {
+        return Err(ConfigError(format!("No TLS configured for '{:}'", name)));
+    }
+    let tls = tls_connector(pop3.tls())?;
+    let stream = open_stream(pop3.host(), pop3.port(), pop3.timeout(), server.proxy())?;
+    let stream = tls.connect(pop3.host(), stream)
+        .map_err(|err| Pop3Error(format!("TLS handshake with {:} failed: {:}", pop3.host(), err)))?;
+    let mut session = Pop3Session::new(stream)?;
+
+    match server.credentials() {
+        Credentials::UsernameAndPassword { username, password } => session.login(name, username, password)?,
+        Credentials::Keyring { username, service } => {
+            let password = keyring_password(name, service, username)?;
+            session.login(name, username, &password)?
+        },
+        Credentials::PasswordCommand { username, password_command } => {
+            let password = command_password(name, password_command)?;
+            session.login(name, username, &password)?
+        },
+        Credentials::OAuth2 { .. } => return Err(ConfigError(format!("OAuth2 is not supported for POP3 on '{:}'", name))),
+        Credentials::None | Credentials::Reference(_) => return Err(ConfigError(format!("No username and password configured for '{:?}'", name))),
+    }
+
+    Ok(session)
+}
+
+/// List the newest messages of the POP3 maildrop of `server`
+pub fn list_messages(server: &Server, pop3: &Pop3, args: &Args) -> Result<Vec<Message>, ConnectionError> {
+    let mut session = connect(server, pop3)?;
+
+    // POP3 message numbers run from 1 (oldest) to the message count (newest)
+    let last = session.stat()?.saturating_sub(args.offset);
+    let first = match args.limit {
+        Some(limit) => last.saturating_sub(limit) + 1,
+        None => 1,
+    };
+
+    let mut messages = Vec::new();
+    for number in first..=last {
+        let raw = if args.body { session.retr(number)? } else { session.top(number)? };
+        messages.push((number, raw));
+    }
+    session.quit()?;
+    sort_messages(&mut messages, args.sort, args.reverse, |(_, raw)| {
+        match mailparse::parse_headers(raw) {
+            Ok((headers, _)) => SortValues {
+                date: headers.get_first_header("Date").and_then(|date| mime::parse_date(date.get_value_raw())),
+                arrival: None,
+                size: None,
+                subject: headers.get_first_value("Subject"),
+                from: headers.get_first_value("From"),
+            },
+            Err(_) => SortValues::default(),
+        }
+    });
+
+    let messages: Vec<Message> = messages.iter()
+        .filter_map(|(_, raw)| Message::from_rfc822(raw, args.body))
+        .collect();
+    if args.thread {
+        let threads = threading::build_threads(&messages);
+        return Ok(threading::flatten(messages, &threads));
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::test_support::MockStream;
+
+    fn session(input: &str) -> Pop3Session<MockStream> {
+        let stream = MockStream::new(input.as_bytes());
+        Pop3Session::new(stream).unwrap()
+    }
+
+    fn sent(session: Pop3Session<MockStream>) -> String {
+        String::from_utf8(session.stream.into_inner().output).unwrap()
+    }
+
+    #[test]
+    fn multiline_responses_are_read_up_to_the_dot_line() {
+        let mut session = session("+OK POP3 ready\r\n\
+            +OK 2 messages\r\nSubject: One\r\n\r\n.\r\n\
+            +OK\r\nSubject: Two\r\n\r\n.\r\n");
+        assert_eq!(session.top(1).unwrap(), b"Subject: One\r\n\r\n");
+        assert_eq!(session.top(2).unwrap(), b"Subject: Two\r\n\r\n");
+        assert_eq!(sent(session), "TOP 1 0\r\nTOP 2 0\r\n");
+    }
+
+    #[test]
+    fn dot_stuffed_lines_are_unstuffed() {
+        let mut session = session("+OK POP3 ready\r\n\
+            +OK message follows\r\nSubject: Dots\r\n\r\n..\r\n...hidden\r\n.not stuffed\r\n.\r\n");
+        assert_eq!(session.retr(1).unwrap(), b"Subject: Dots\r\n\r\n.\r\n..hidden\r\n.not stuffed\r\n");
+    }
+
+    #[test]
+    fn err_responses_are_pop3_errors() {
+        let mut session = session("+OK POP3 ready\r\n-ERR no such message\r\n+OK 3 1200\r\n");
+        assert!(matches!(session.retr(9), Err(Pop3Error(msg)) if msg == "no such message"));
+        // the session goes on after an error
+        assert_eq!(session.stat().unwrap(), 3);
+    }
+
+    #[test]
+    fn rejected_password_is_an_authentication_error() {
+        let mut session = session("+OK POP3 ready\r\n+OK\r\n-ERR [AUTH] invalid password\r\n");
+        let err = session.login("mail", "alice", "secret").err().unwrap();
+        assert!(matches!(&err, AuthError(msg) if msg == "Invalid credentials for mail: [AUTH] invalid password"), "unexpected error {:?}", err);
+        assert_eq!(sent(session), "USER alice\r\nPASS secret\r\n");
+    }
+
+    #[test]
+    fn truncated_multiline_response_fails() {
+        let mut session = session("+OK POP3 ready\r\n+OK\r\nSubject: Cut\r\n");
+        assert!(session.top(1).is_err());
+    }
+}
@@ -0,0 +1,43 @@
+use std::io::{self, IsTerminal, Write};
+
+use crate::args::Format;
+
+/// Count of fetched messages, redrawn in place on stderr while a long fetch runs
+pub struct Progress {
+    label: String,
+    total: usize,
+    fetched: usize,
+    /// Whether anything is drawn at all
+    shown: bool,
+}
+
+impl Progress {
+    /// Progress of fetching `total` messages in batches of `batch_size`, shown only when it takes
+    /// more than one batch and the output is for a person to read: on a terminal, not as JSON and not `--quiet`
+    pub fn new(label: String, total: usize, batch_size: usize, format: Format) -> Self {
+        let shown = total > batch_size
+            && !matches!(format, Format::Json | Format::Ndjson)
+            && log_enabled!(log::Level::Warn)
+            && io::stdout().is_terminal()
+            && io::stderr().is_terminal();
+        Progress { label, total, fetched: 0, shown }
+    }
+
+    /// Count `count` more fetched messages
+    pub fn add(&mut self, count: usize) {
+        self.fetched += count;
+        if self.shown {
+            eprint!("\r{:}: fetched {:}/{:} messages", self.label, self.fetched, self.total);
+            let _ = io::stderr().flush();
+        }
+    }
+}
+
+impl Drop for Progress {
+    /// Clear the progress line, leaving the terminal for the output
+    fn drop(&mut self) {
+        if self.shown && self.fetched > 0 {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use std::env;
+use std::io::{self, IsTerminal, Write};
+
+use crate::args::{Args, Color, Column, Field, Format, Template, TemplatePart};
+use crate::message::{Address, DisplayAddresses, Message};
+
+/// Table width when the output is not a terminal
+const DEFAULT_TABLE_WIDTH: usize = 80;
+/// Narrowest a table column is truncated to
+const MIN_COLUMN_WIDTH: usize = 5;
+
+/// ANSI styles of the colorized text format
+const UNSEEN_STYLE: &str = "1";
+const SENDER_STYLE: &str = "36";
+const DATE_STYLE: &str = "33";
+
+/// What to print of each message, as requested on the command line
+pub struct Layout<'a> {
+    /// Columns of the table format
+    pub columns: &'a [Column],
+    /// Width the table format is fitted into
+    pub width: usize,
+    /// Envelope fields of the text format
+    pub show: &'a [Field],
+    /// Most addresses to print of each address list in the text format, or all of them
+    pub max_addresses: Option<usize>,
+    /// Whether the text format is colorized with ANSI escape codes
+    pub color: bool,
+    /// Line to print for each message in the text format instead of its fields
+    pub template: Option<&'a Template>,
+    /// Whether the `---` separators between the messages of the text format are left out
+    pub quiet: bool,
+}
+
+impl<'a> Layout<'a> {
+    pub fn new(args: &'a Args) -> Self {
+        Layout {
+            columns: &args.columns,
+            width: terminal_size::terminal_size().map_or(DEFAULT_TABLE_WIDTH, |(width, _)| width.0 as usize),
+            show: &args.show,
+            max_addresses: Some(args.max_addresses).filter(|_| !args.full),
+            color: match args.color {
+                Color::Always => true,
+                Color::Never => false,
+                Color::Auto => io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none(),
+            },
+            template: args.template.as_ref(),
+            quiet: args.quiet,
+        }
+    }
+}
+
+/// Print the `messages` in the requested output format and `layout`
+pub fn render(messages: &[Message], format: Format, layout: &Layout, out: &mut dyn Write) -> io::Result<()> {
+    match format {
+        Format::Text => match layout.template {
+            Some(template) => render_template(messages, template, out),
+            None => render_text(messages, layout, out),
+        },
+        Format::Table => render_table(messages, layout.columns, layout.width, out),
+        Format::Json => render_json(messages, out),
+        Format::Ndjson => render_ndjson(messages, out),
+    }
+}
+
+fn render_text(messages: &[Message], layout: &Layout, out: &mut dyn Write) -> io::Result<()> {
+    for message in messages {
+        // replies are indented by their depth in the conversation thread
+        let indent = "  ".repeat(message.thread_depth.unwrap_or(0));
+        let mut block = Vec::new();
+        render_message(message, layout, &mut block)?;
+        for line in block.split_inclusive(|&byte| byte == b'\n') {
+            out.write_all(indent.as_bytes())?;
+            out.write_all(line)?;
+        }
+    }
+    Ok(())
+}
+
+fn render_template(messages: &[Message], template: &Template, out: &mut dyn Write) -> io::Result<()> {
+    for message in messages {
+        let line: String = template.parts().iter()
+            .map(|part| match part {
+                TemplatePart::Text(text) => text.clone(),
+                TemplatePart::Column(column) => cell(message, *column),
+            })
+            .collect();
+        writeln!(out, "{:}", line)?;
+    }
+    Ok(())
+}
+
+fn render_message(message: &Message, layout: &Layout, out: &mut dyn Write) -> io::Result<()> {
+    let show = layout.show;
+    if !layout.quiet {
+        writeln!(out, "---")?;
+    }
+    if let Some(uid) = message.uid {
+        writeln!(out, "UID: {:}", uid)?;
+    }
+    let addresses = [
+        ("From", Field::From, &message.from),
+        ("Reply-To", Field::From, &message.reply_to),
+        ("Sender", Field::From, &message.sender),
+        ("To", Field::To, &message.to),
+        ("Cc", Field::Cc, &message.cc),
+        ("Bcc", Field::Bcc, &message.bcc),
+    ];
+    for (key, _, list) in addresses.iter().filter(|(_, field, _)| show.contains(field)) {
+        let list = match list {
+            Some(list) => list,
+            None => continue,
+        };
+        // the envelope repeats the From addresses as Reply-To and Sender when the message has neither
+        if *key != "From" && message.is_from(list) {
+            continue;
+        }
+        let addresses = truncated_addresses(list, layout.max_addresses);
+        let addresses = if *key == "From" { paint(&addresses, SENDER_STYLE, layout.color) } else { addresses };
+        writeln!(out, "{:}: {:}", key, addresses)?;
+    }
+    if show.contains(&Field::Date) {
+        if let Some(date) = &message.date {
+            writeln!(out, "Date: {:}", paint(&date.to_rfc2822(), DATE_STYLE, layout.color))?;
+        } else if let Some(date) = &message.raw_date {
+            writeln!(out, "Date: {:}", paint(date, DATE_STYLE, layout.color))?;
+        }
+    }
+    if let Some(received) = message.internal_date.as_ref().filter(|_| show.contains(&Field::Received)) {
+        writeln!(out, "Received: {:}", received.to_rfc2822())?;
+    }
+    if let Some(size) = message.size.filter(|_| show.contains(&Field::Size)) {
+        writeln!(out, "Size: {:}", human_size(size))?;
+    }
+    if let Some(labels) = message.gmail_labels.as_ref().filter(|labels| !labels.is_empty()) {
+        writeln!(out, "Labels: {:}", labels.join(", "))?;
+    }
+    let flags = message.flags.as_deref().map(flag_indicator).filter(|flags| !flags.is_empty());
+    let unseen = message.flags.as_ref().is_some_and(|flags| !flags.iter().any(|flag| flag.eq_ignore_ascii_case("\\Seen")));
+    let subject = message.subject.as_deref().filter(|_| show.contains(&Field::Subject))
+        .map(|subject| if unseen { paint(subject, UNSEEN_STYLE, layout.color) } else { subject.to_string() });
+    match (subject, flags) {
+        (Some(subject), Some(flags)) => writeln!(out, "Subject: [{:}] {:}", flags, subject)?,
+        (Some(subject), None) => writeln!(out, "Subject: {:}", subject)?,
+        (None, Some(flags)) => writeln!(out, "Flags: [{:}]", flags)?,
+        (None, None) => {},
+    }
+    if let Some(body) = &message.body {
+        writeln!(out)?;
+        writeln!(out, "{:}", body)?;
+    }
+    Ok(())
+}
+
+/// The `text` in the ANSI `style` when colorizing
+fn paint(text: &str, style: &str, color: bool) -> String {
+    if color {
+        format!("\x1b[{:}m{:}\x1b[0m", style, text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// The addresses of `list`, only the first `max` of them followed by how many more there are
+fn truncated_addresses(list: &[Address], max: Option<usize>) -> String {
+    match max {
+        Some(max) if list.len() > max => format!("{:} and {:} more", DisplayAddresses(&list[..max]), list.len() - max),
+        _ => DisplayAddresses(list).to_string(),
+    }
+}
+
+fn render_table(messages: &[Message], columns: &[Column], width: usize, out: &mut dyn Write) -> io::Result<()> {
+    let rows: Vec<Vec<String>> = messages.iter()
+        .map(|message| columns.iter().map(|column| cell(message, *column)).collect())
+        .collect();
+    let mut widths: Vec<usize> = columns.iter().enumerate()
+        .map(|(index, column)| rows.iter().map(|row| row[index].chars().count()).fold(column.title().len(), usize::max))
+        .collect();
+
+    // narrow the widest columns until the table fits the width, with two spaces between columns
+    let available = width.saturating_sub(2 * columns.len().saturating_sub(1));
+    while widths.iter().sum::<usize>() > available {
+        match widths.iter_mut().filter(|width| **width > MIN_COLUMN_WIDTH).max() {
+            Some(widest) => *widest -= 1,
+            None => break,
+        }
+    }
+
+    let titles: Vec<String> = columns.iter().map(|column| column.title().to_string()).collect();
+    for row in std::iter::once(&titles).chain(&rows) {
+        let cells: Vec<String> = row.iter().zip(&widths)
+            .map(|(value, &width)| format!("{:<width$}", truncate(value, width), width = width))
+            .collect();
+        writeln!(out, "{:}", cells.join("  ").trim_end())?;
+    }
+    Ok(())
+}
+
+/// Value of the table `column` for the `message`, on a single line
+fn cell(message: &Message, column: Column) -> String {
+    let value = match column {
+        Column::Uid => message.uid.map(|uid| uid.to_string()),
+        Column::Date => message.date.map(|date| date.format("%Y-%m-%d %H:%M").to_string()).or_else(|| message.raw_date.clone()),
+        Column::Received => message.internal_date.map(|date| date.format("%Y-%m-%d %H:%M").to_string()),
+        Column::Size => message.size.map(human_size),
+        Column::From => message.from.as_deref().map(|list| DisplayAddresses(list).to_string()),
+        Column::To => message.to.as_deref().map(|list| DisplayAddresses(list).to_string()),
+        Column::Subject => message.subject.clone(),
+        Column::Flags => message.flags.as_deref().map(flag_indicator),
+    };
+    let value = value.unwrap_or_default().split_whitespace().collect::<Vec<_>>().join(" ");
+    match (column, message.thread_depth) {
+        (Column::Subject, Some(depth)) => format!("{:}{:}", "  ".repeat(depth), value),
+        _ => value,
+    }
+}
+
+/// The `value` cut to `width` characters, ending in an ellipsis when it was longer
+fn truncate(value: &str, width: usize) -> String {
+    if value.chars().count() <= width {
+        value.to_string()
+    } else {
+        value.chars().take(width.saturating_sub(1)).chain(std::iter::once('…')).collect()
+    }
+}
+
+/// Size in bytes, or in KB or MB with one decimal for larger sizes
+pub fn human_size(size: u32) -> String {
+    const KB: f64 = 1024.0;
+    match f64::from(size) {
+        bytes if bytes < KB => format!("{:} B", size),
+        bytes if bytes < KB * KB => format!("{:.1} KB", bytes / KB),
+        bytes => format!("{:.1} MB", bytes / (KB * KB)),
+    }
+}
+
+/// Compact indicator of the message flags: N for unseen (new), A for answered, F for flagged,
+/// D for deleted and T for draft messages, followed by any custom keywords verbatim
+fn flag_indicator(flags: &[String]) -> String {
+    let has = |flag: &str| flags.iter().any(|set| set.eq_ignore_ascii_case(flag));
+    let mut indicator: Vec<&str> = [
+        (!has("\\Seen"), "N"),
+        (has("\\Answered"), "A"),
+        (has("\\Flagged"), "F"),
+        (has("\\Deleted"), "D"),
+        (has("\\Draft"), "T"),
+    ].iter().filter(|(set, _)| *set).map(|(_, letter)| *letter).collect();
+    indicator.extend(flags.iter().filter(|flag| !flag.starts_with('\\')).map(String::as_str));
+    indicator.join(" ")
+}
+
+fn render_json(messages: &[Message], out: &mut dyn Write) -> io::Result<()> {
+    let json = serde_json::to_string_pretty(messages).expect("Messages are always serializable");
+    writeln!(out, "{:}", json)
+}
+
+fn render_ndjson(messages: &[Message], out: &mut dyn Write) -> io::Result<()> {
+    for message in messages {
+        let json = serde_json::to_string(message).expect("Messages are always serializable");
+        writeln!(out, "{:}", json)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use super::*;
+
+    fn message(headers: &str) -> Message {
+        Message::from_rfc822(headers.as_bytes(), false).unwrap()
+    }
+
+    fn table(messages: &[Message], columns: &[Column], width: usize) -> Vec<String> {
+        let mut out = Vec::new();
+        render_table(messages, columns, width, &mut out).unwrap();
+        String::from_utf8(out).unwrap().lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn received_date_and_size_are_only_printed_when_shown() {
+        let mut message = message("From: alice@example.com\r\nSubject: Hello\r\n\r\n");
+        message.internal_date = DateTime::parse_from_rfc2822("Tue, 15 Jul 2003 10:52:37 +0200").ok();
+        message.size = Some(2048);
+        let text = |show: &[Field]| {
+            let layout = Layout { columns: &[], width: 80, show, max_addresses: None, color: false, template: None, quiet: false };
+            let mut out = Vec::new();
+            render_text(std::slice::from_ref(&message), &layout, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let all = text(&[Field::Received, Field::Size]);
+        assert!(all.contains("Received: Tue, 15 Jul 2003 10:52:37 +0200\n"));
+        assert!(all.contains("Size: 2.0 KB\n"));
+        let none = text(&[Field::From, Field::Subject]);
+        assert!(!none.contains("Received:") && !none.contains("Size:"));
+    }
+
+    #[test]
+    fn quiet_text_has_no_separators() {
+        let messages = [message("Subject: One\r\n\r\n"), message("Subject: Two\r\n\r\n")];
+        let text = |quiet| {
+            let layout = Layout { columns: &[], width: 80, show: &[Field::Subject], max_addresses: None, color: false, template: None, quiet };
+            let mut out = Vec::new();
+            render_text(&messages, &layout, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(text(false), "---\nSubject: One\n---\nSubject: Two\n");
+        assert_eq!(text(true), "Subject: One\nSubject: Two\n");
+    }
+
+    #[test]
+    fn template_is_rendered_as_one_line_per_message() {
+        let mut message = message("From: Alice <alice@example.com>\r\nSubject: Hello\r\n\r\n");
+        message.uid = Some(7);
+        let template: Template = "{{{uid}}} {from}: {subject}".parse().unwrap();
+        let mut out = Vec::new();
+        render_template(&[message], &template, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "{7} Alice <alice@example.com>: Hello\n");
+    }
+
+    #[test]
+    fn multibyte_values_are_truncated_by_characters() {
+        assert_eq!(truncate("Grüße aus Köln", 6), "Grüße…");
+        assert_eq!(truncate("Grüße", 5), "Grüße");
+        assert_eq!(truncate("日本語のメール", 3), "日本…");
+    }
+
+    #[test]
+    fn widest_columns_are_narrowed_to_fit() {
+        let messages = [message("From: alice@example.com\r\nSubject: A rather long subject line\r\n\r\n")];
+        let lines = table(&messages, &[Column::From, Column::Subject], 30);
+        assert_eq!(lines, vec![
+            "From            Subject".to_string(),
+            "alice@example…  A rather long…".to_string(),
+        ]);
+        assert!(lines.iter().all(|line| line.chars().count() <= 30));
+    }
+
+    #[test]
+    fn columns_are_not_narrowed_below_the_minimum() {
+        let messages = [message("From: alice@example.com\r\nSubject: Hello world\r\n\r\n")];
+        let lines = table(&messages, &[Column::From, Column::Subject], 4);
+        assert_eq!(lines, vec!["From   Subj…".to_string(), "alic…  Hell…".to_string()]);
+    }
+
+    #[test]
+    fn cells_are_single_lines_indented_by_thread_depth() {
+        let mut reply = message("Subject: Re:\r\n  folded\tsubject\r\n\r\n");
+        assert_eq!(cell(&reply, Column::Subject), "Re: folded subject");
+        reply.thread_depth = Some(2);
+        assert_eq!(cell(&reply, Column::Subject), "    Re: folded subject");
+        assert_eq!(cell(&reply, Column::Uid), "");
+    }
+}
@@ -0,0 +1,169 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::process::{self, Command};
+
+use mailparse::MailHeaderMap;
+
+use crate::message::Message;
+
+/// Editor to compose messages in when neither VISUAL nor EDITOR is set
+const DEFAULT_EDITOR: &str = "vi";
+
+/// Reply to a message, with the original text body quoted
+#[derive(Debug)]
+pub struct Reply {
+    /// Recipients, the Reply-To addresses of the original message or else its sender
+    pub to: String,
+    pub subject: String,
+    /// Message ID of the original message
+    pub in_reply_to: Option<String>,
+    /// Message IDs of the thread, from its first message up to the original message
+    pub references: Vec<String>,
+    pub body: String,
+}
+
+impl Reply {
+    /// Reply to the raw `original` message, or `None` if it cannot be parsed or has no sender
+    pub fn to_message(original: &[u8]) -> Option<Reply> {
+        let (headers, _) = mailparse::parse_headers(original).ok()?;
+        let from = headers.get_first_value("From")?;
+        let to = headers.get_first_value("Reply-To").unwrap_or_else(|| from.clone());
+        let message = Message::from_rfc822(original, true)?;
+
+        let subject = message.subject.unwrap_or_default();
+        let subject = if subject.get(..3).is_some_and(|prefix| prefix.eq_ignore_ascii_case("Re:")) {
+            subject
+        } else {
+            format!("Re: {:}", subject)
+        };
+
+        // the references of the original message, or the message it replied to, followed by its own ID (RFC 5322)
+        let mut references = message.references;
+        if references.is_empty() {
+            references.extend(message.in_reply_to);
+        }
+        references.extend(message.message_id.clone());
+
+        let attribution = match message.raw_date {
+            Some(date) => format!("On {:}, {:} wrote:", date, from),
+            None => format!("{:} wrote:", from),
+        };
+        let body = format!("\n\n{:}\n{:}", attribution, quote(message.body.as_deref().unwrap_or_default()));
+
+        Some(Reply { to, subject, in_reply_to: message.message_id, references, body })
+    }
+}
+
+/// The `text` with each line prefixed with `> `
+fn quote(text: &str) -> String {
+    text.lines()
+        .map(|line| if line.is_empty() { ">\n".to_string() } else { format!("> {:}\n", line) })
+        .collect()
+}
+
+/// Let the user edit the `text` in their editor, returning the edited text
+pub fn edit(text: &str) -> io::Result<String> {
+    let (path, mut file) = create_temp_file()?;
+    let written = file.write_all(text.as_bytes());
+    drop(file);
+    if let Err(err) = written {
+        let _ = fs::remove_file(&path);
+        return Err(err);
+    }
+
+    let editor = env::var("VISUAL").or_else(|_| env::var("EDITOR")).unwrap_or_else(|_| DEFAULT_EDITOR.to_string());
+    let mut words = editor.split_whitespace();
+    let status = Command::new(words.next().unwrap_or(DEFAULT_EDITOR)).args(words).arg(&path).status();
+    let edited = fs::read_to_string(&path);
+    let _ = fs::remove_file(&path);
+
+    match status? {
+        status if status.success() => edited,
+        status => Err(io::Error::other(format!("Editor '{:}' failed: {:}", editor, status))),
+    }
+}
+
+/// Create a new file in the temporary directory that only the user can read, never opening an existing
+/// file or a link another user placed there under the same name
+fn create_temp_file() -> io::Result<(PathBuf, File)> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    for attempt in 0..100 {
+        let path = env::temp_dir().join(format!("postkast-{:}-{:}.txt", process::id(), attempt));
+        match options.open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AlreadyExists, "Cannot create a temporary file to edit the message in"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply_continues_the_thread() {
+        let original = b"From: Alice <alice@example.com>\r\n\
+            Subject: Lunch\r\n\
+            Date: Mon, 7 Feb 1994 21:52:25 -0800\r\n\
+            Message-ID: <2@example.com>\r\n\
+            In-Reply-To: <1@example.com>\r\n\
+            \r\n\
+            Tomorrow?\r\n";
+        let reply = Reply::to_message(original).unwrap();
+        assert_eq!(reply.to, "Alice <alice@example.com>");
+        assert_eq!(reply.subject, "Re: Lunch");
+        assert_eq!(reply.in_reply_to.as_deref(), Some("<2@example.com>"));
+        assert_eq!(reply.references, vec!["<1@example.com>", "<2@example.com>"]);
+        assert_eq!(reply.body, "\n\nOn Mon, 7 Feb 1994 21:52:25 -0800, Alice <alice@example.com> wrote:\n> Tomorrow?\n");
+    }
+
+    #[test]
+    fn reply_keeps_the_references_and_re_prefix() {
+        let original = b"From: alice@example.com\r\n\
+            Reply-To: list@example.com\r\n\
+            Subject: RE: Lunch\r\n\
+            Message-ID: <3@example.com>\r\n\
+            In-Reply-To: <2@example.com>\r\n\
+            References: <1@example.com> <2@example.com>\r\n\
+            \r\n\
+            Sure\r\n";
+        let reply = Reply::to_message(original).unwrap();
+        assert_eq!(reply.to, "list@example.com");
+        assert_eq!(reply.subject, "RE: Lunch");
+        assert_eq!(reply.references, vec!["<1@example.com>", "<2@example.com>", "<3@example.com>"]);
+    }
+
+    #[test]
+    fn message_without_sender_cannot_be_replied_to() {
+        assert!(Reply::to_message(b"Subject: Anonymous\r\n\r\nHello\r\n").is_none());
+    }
+
+    #[test]
+    fn empty_lines_are_quoted_without_a_trailing_space() {
+        assert_eq!(quote("Hello\n\nBye"), "> Hello\n>\n> Bye\n");
+        assert_eq!(quote(""), "");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn temp_file_is_private_and_new() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (first, _) = create_temp_file().unwrap();
+        let (second, _) = create_temp_file().unwrap();
+        let mode = fs::metadata(&first).unwrap().permissions().mode();
+        fs::remove_file(&first).unwrap();
+        fs::remove_file(&second).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
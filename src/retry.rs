@@ -0,0 +1,30 @@
+use std::thread;
+use std::time::Duration;
+
+/// Delay before the first retry, doubled after every failed attempt
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Run `operation`, retrying it up to `retries` more times with exponential backoff
+/// as long as it fails with an error that `is_transient` accepts.
+///
+/// Each retry is logged as a warning, mentioning `what` was attempted.
+pub fn with_retries<T, E, F, P>(what: &str, retries: u32, is_transient: P, mut operation: F) -> Result<T, E>
+where
+    F: FnMut() -> Result<T, E>,
+    P: Fn(&E) -> bool,
+{
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        match operation() {
+            Err(err) if attempt < retries && is_transient(&err) => {
+                attempt += 1;
+                warn!("{:} failed, retrying in {:}s (attempt {:} of {:})",
+                    what, backoff.as_secs(), attempt, retries);
+                thread::sleep(backoff);
+                backoff *= 2;
+            },
+            result => return result,
+        }
+    }
+}
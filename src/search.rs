@@ -0,0 +1,90 @@
+use chrono::NaiveDate;
+
+/// Build the criteria of an IMAP SEARCH command from command line arguments.
+///
+/// Arguments that are plain atoms, such as the `FROM` or `UNSEEN` search keys, a date
+/// or a sequence set, are passed on as they are. Anything else is sent as a quoted string,
+/// so `SUBJECT "weekly report"` works as expected. Control characters cannot be sent
+/// in a quoted string and are rejected, as are unbalanced parentheses.
+pub fn search_query(criteria: &[String]) -> Result<String, String> {
+    if criteria.is_empty() {
+        return Err("No search criteria given".to_string());
+    }
+
+    let arguments = criteria.iter()
+        .map(|argument| search_argument(argument))
+        .collect::<Result<Vec<_>, _>>()?;
+    let query = arguments.join(" ");
+
+    // parentheses inside quoted strings are part of the string
+    let mut depth = 0;
+    let atoms = arguments.iter().filter(|argument| !argument.starts_with('"'));
+    for c in atoms.flat_map(|atom| atom.chars()).filter(|&c| c == '(' || c == ')') {
+        depth += if c == '(' { 1 } else { -1 };
+        if depth < 0 {
+            break;
+        }
+    }
+    if depth != 0 {
+        return Err(format!("Unbalanced parentheses in search criteria: {:}", query));
+    }
+
+    // non-ASCII search strings are only understood when the charset is declared
+    if query.is_ascii() {
+        Ok(query)
+    } else {
+        Ok(format!("CHARSET UTF-8 {:}", query))
+    }
+}
+
+fn search_argument(argument: &str) -> Result<String, String> {
+    if argument.chars().any(char::is_control) {
+        Err(format!("Search criteria cannot contain control characters: {:?}", argument))
+    } else if !argument.is_empty() && argument.chars().all(is_atom_char) {
+        Ok(argument.to_string())
+    } else {
+        Ok(format!("\"{:}\"", argument.replace('\\', "\\\\").replace('"', "\\\"")))
+    }
+}
+
+/// Characters of an atom (RFC 3501), with `*` of sequence sets and the parentheses
+/// used for grouping search keys
+fn is_atom_char(c: char) -> bool {
+    c.is_ascii_graphic() && !matches!(c, '{' | '%' | '"' | '\\' | ']')
+}
+
+/// Search keys for messages received on or after `since` and before `before`, with the dates
+/// in the `d-Mon-yyyy` format of IMAP. The keys of a search are combined with AND.
+pub fn date_criteria(since: Option<NaiveDate>, before: Option<NaiveDate>) -> Vec<String> {
+    let mut criteria = Vec::new();
+    if let Some(since) = since {
+        criteria.push("SINCE".to_string());
+        criteria.push(since.format("%-d-%b-%Y").to_string());
+    }
+    if let Some(before) = before {
+        criteria.push("BEFORE".to_string());
+        criteria.push(before.format("%-d-%b-%Y").to_string());
+    }
+    criteria
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(criteria: &[&str]) -> Result<String, String> {
+        search_query(&criteria.iter().map(|argument| argument.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn parentheses_in_quoted_strings_are_not_counted() {
+        assert_eq!(query(&["SUBJECT", "a (b"]), Ok("SUBJECT \"a (b\"".to_string()));
+        assert_eq!(query(&["(SUBJECT", "a (b", "FROM", "alice)"]), Ok("(SUBJECT \"a (b\" FROM alice)".to_string()));
+    }
+
+    #[test]
+    fn unbalanced_parentheses_are_rejected() {
+        assert!(query(&["(FROM", "alice"]).is_err());
+        assert_eq!(query(&["(FROM", "alice)"]), Ok("(FROM alice)".to_string()));
+    }
+}
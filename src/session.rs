@@ -0,0 +1,468 @@
+use std::collections::HashSet;
+use std::io;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, FixedOffset};
+use hmac::{Hmac, Mac};
+use imap::{Authenticator, Client, Session};
+use imap::extensions::idle::Handle;
+use imap::types::{Fetch, Flag, Mailbox, Name, Uid, UnsolicitedResponse, ZeroCopy};
+use md5::Md5;
+use imap::Error::{Bad, No, Parse, TlsHandshake};
+use imap_proto::types::Capability;
+
+use crate::settings::{AuthMechanism, Credentials, Imap, Server};
+use crate::stream::{self, ImapStream};
+use crate::ConnectionError::{self, ConfigError, TimeoutError};
+use crate::{command_password, connect_first, keyring_password, login_error, open_stream, retry, tls_connector};
+
+/// SASL XOAUTH2 authenticator (https://developers.google.com/gmail/imap/xoauth2-protocol)
+struct XOAuth2<'a> {
+    username: &'a str,
+    access_token: &'a str,
+}
+
+impl<'a> Authenticator for XOAuth2<'a> {
+    type Response = String;
+
+    fn process(&self, _challenge: &[u8]) -> Self::Response {
+        format!("user={}\x01auth=Bearer {}\x01\x01", self.username, self.access_token)
+    }
+}
+
+/// SASL PLAIN authenticator (RFC 4616)
+struct Plain<'a> {
+    username: &'a str,
+    password: &'a str,
+}
+
+impl<'a> Authenticator for Plain<'a> {
+    type Response = String;
+
+    fn process(&self, _challenge: &[u8]) -> Self::Response {
+        format!("\0{}\0{}", self.username, self.password)
+    }
+}
+
+/// SASL CRAM-MD5 authenticator (RFC 2195), answering the challenge with its HMAC-MD5 keyed by the password
+struct CramMd5<'a> {
+    username: &'a str,
+    password: &'a str,
+}
+
+impl<'a> Authenticator for CramMd5<'a> {
+    type Response = String;
+
+    fn process(&self, challenge: &[u8]) -> Self::Response {
+        let mut mac = Hmac::<Md5>::new_from_slice(self.password.as_bytes()).expect("HMAC accepts keys of any length");
+        mac.update(challenge);
+        let digest: String = mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+        format!("{} {}", self.username, digest)
+    }
+}
+
+/// TCP connection to the IMAP server, to the addresses resolved by `Imap` unless going through a proxy
+fn open_imap_stream(server: &Server, imap: &Imap) -> io::Result<TcpStream> {
+    match server.proxy() {
+        Some(proxy) => open_stream(imap.host(), imap.connect_port(), imap.timeout(), Some(proxy)),
+        None => connect_first(imap.host(), imap, imap.address_family(), imap.timeout()),
+    }
+}
+
+/// Log in with a password, using the LOGIN command unless another `mechanism` is configured
+fn password_login(client: Client<ImapStream>, name: &str, mechanism: Option<AuthMechanism>,
+                  username: &str, password: &str) -> Result<Session<ImapStream>, ConnectionError> {
+    match mechanism.unwrap_or(AuthMechanism::Login) {
+        AuthMechanism::Login => client.login(username, password),
+        AuthMechanism::Plain => client.authenticate("PLAIN", &Plain { username, password }),
+        AuthMechanism::CramMd5 => client.authenticate("CRAM-MD5", &CramMd5 { username, password }),
+        AuthMechanism::XOAuth2 => return Err(ConfigError(format!("XOAUTH2 authentication needs OAuth2 credentials for '{:}'", name))),
+    }.map_err(|(err, _)| login_error(name, err))
+}
+
+/// Authenticated IMAP session with a configured server, offering the commands of the `imap::Session`
+/// that only read messages unless changing them is permitted
+pub struct ImapSession {
+    session: Session<ImapStream>,
+    /// Name of the configured server
+    name: String,
+    /// Name of the selected folder, if any
+    selected: Option<String>,
+    /// UIDVALIDITY of the selected folder, if the server reported one
+    uid_validity: Option<u32>,
+    /// HIGHESTMODSEQ of the selected folder, once looked up with `query_highest_modseq`
+    highest_modseq: Option<u64>,
+    /// Time between NOOP commands sent by `keepalive`
+    keepalive: Duration,
+    /// Time of logging in or of the last NOOP sent by `keepalive`
+    last_keepalive: Instant,
+    /// Capabilities the server advertised after logging in, queried on first use
+    capabilities: Option<Vec<String>>,
+    /// Whether the commands changing messages are permitted, otherwise they fail without being sent
+    writable: bool,
+}
+
+impl ImapSession {
+    /// Connect and log in to the server, retrying transient failures up to `retries` times.
+    /// The session only changes messages if `writable`, as permitted with `--write`.
+    pub fn connect(server: &Server, retries: u32, writable: bool) -> Result<Self, ConnectionError> {
+        let imap = server.imap()
+            .ok_or_else(|| ConfigError(format!("No IMAP configured for '{:}'", server.name())))?;
+        info!("Connecting to server \"{:}\" at {:}:{:}", server.name(), imap.host(), imap.connect_port());
+
+        let what = format!("Connecting to \"{:}\"", server.name());
+        retry::with_retries(&what, retries, ConnectionError::is_transient, || {
+            ImapSession::connect_once(server, imap, writable).map_err(|err| if err.is_timeout() {
+                TimeoutError(format!("No response from \"{:}\" within {:}s",
+                    server.name(), imap.timeout().as_secs()))
+            } else {
+                err
+            })
+        })
+    }
+
+    fn connect_once(server: &Server, imap: &Imap, writable: bool) -> Result<Self, ConnectionError> {
+        let credentials = server.credentials();
+        let name = server.name();
+
+        let domain = imap.tls_domain();
+        let tls = tls_connector(imap.tls())?;
+        let compressed = Arc::new(AtomicBool::new(false));
+        let client = match (imap.tls(), imap.starttls()) {
+            (Some(_), false) => {
+                let stream = tls.connect(domain, open_imap_stream(server, imap)?).map_err(TlsHandshake)?;
+                let mut client = Client::new(ImapStream::tls(stream, compressed.clone()));
+                client.read_greeting()?;
+                client
+            },
+            (None, true) => {
+                let stream = stream::starttls(open_imap_stream(server, imap)?, domain, &tls)?;
+                Client::new(ImapStream::tls(stream, compressed.clone()))
+            },
+            (Some(_), true) => return Err(ConfigError(format!("Both TLS and STARTTLS configured for '{:}'", name))),
+            (None, false) if imap.allow_insecure() => {
+                warn!("Connecting to \"{:}\" WITHOUT TLS, the password and messages are sent unencrypted", name);
+                let stream = open_imap_stream(server, imap)?;
+                let mut client = Client::new(ImapStream::plain(stream, compressed.clone()));
+                client.read_greeting()?;
+                client
+            },
+            (None, false) => return Err(ConfigError(format!("No TLS configured for '{:}'", name))),
+        };
+
+        // the client we have here is unauthenticated.
+        // to do anything useful with the e-mails, we need to log in
+        let mechanism = imap.auth_mechanism();
+        let session = match credentials {
+            Credentials::UsernameAndPassword { username, password } => password_login(client, name, mechanism, username, password)?,
+            Credentials::OAuth2 { username, access_token, .. } => match mechanism {
+                None | Some(AuthMechanism::XOAuth2) => {
+                    let auth = XOAuth2 { username, access_token };
+                    client.authenticate("XOAUTH2", &auth)
+                        .map_err(|(err, _)| login_error(name, err))?
+                },
+                Some(mechanism) => return Err(ConfigError(format!("{:} authentication needs a password, not an OAuth2 token, for '{:}'",
+                    mechanism.name(), name))),
+            },
+            Credentials::Keyring { username, service } => {
+                let password = keyring_password(name, service, username)?;
+                password_login(client, name, mechanism, username, &password)?
+            },
+            Credentials::PasswordCommand { username, password_command } => {
+                let password = command_password(name, password_command)?;
+                password_login(client, name, mechanism, username, &password)?
+            },
+            Credentials::None | Credentials::Reference(_) => return Err(ConfigError(format!("No username and password configured for '{:?}'", name))),
+        };
+
+        let mut session = ImapSession {
+            session,
+            name: name.to_string(),
+            selected: None,
+            uid_validity: None,
+            highest_modseq: None,
+            keepalive: imap.keepalive(),
+            last_keepalive: Instant::now(),
+            capabilities: None,
+            writable,
+        };
+        if imap.compress() {
+            session.start_compression(&compressed)?;
+        }
+        Ok(session)
+    }
+
+    /// Compress the rest of the session with COMPRESS=DEFLATE (RFC 4978),
+    /// staying uncompressed when the server does not support it
+    fn start_compression(&mut self, compressed: &AtomicBool) -> Result<(), ConnectionError> {
+        if !self.has_capability("COMPRESS=DEFLATE")? {
+            debug!("Server \"{:}\" does not support compression", self.name);
+            return Ok(());
+        }
+        match self.session.run_command_and_check_ok("COMPRESS DEFLATE") {
+            Ok(()) => {
+                compressed.store(true, Ordering::SeqCst);
+                debug!("Compressing the session with \"{:}\"", self.name);
+                Ok(())
+            },
+            Err(No(reason)) => {
+                debug!("Server \"{:}\" refused compression: {:}", self.name, reason);
+                Ok(())
+            },
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Name of the configured server of the session
+    pub fn server_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Name of the folder selected with `select`, if any
+    pub fn selected_folder(&self) -> Option<&str> {
+        self.selected.as_deref()
+    }
+
+    /// Send a NOOP when the configured keepalive interval has passed since logging in or the previous one,
+    /// for calling between the steps of long operations so that the server does not drop the connection
+    pub fn keepalive(&mut self) -> Result<(), ConnectionError> {
+        if self.last_keepalive.elapsed() >= self.keepalive {
+            debug!("Keeping the session with \"{:}\" alive", self.name);
+            self.session.noop()?;
+            self.last_keepalive = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Select the `folder` for the following commands, remembering its UIDVALIDITY
+    pub fn select(&mut self, folder: &str) -> Result<Mailbox, ConnectionError> {
+        let mailbox = self.session.select(folder)?;
+        self.selected = Some(folder.to_string());
+        self.uid_validity = mailbox.uid_validity;
+        self.highest_modseq = None;
+        Ok(mailbox)
+    }
+
+    /// UIDVALIDITY of the selected folder, which changes when its UIDs refer to other messages than before
+    pub fn uid_validity(&self) -> Option<u32> {
+        self.uid_validity
+    }
+
+    /// Look up the HIGHESTMODSEQ of the selected `folder` with the CONDSTORE extension (RFC 7162),
+    /// the modification sequence of its most recent change
+    pub fn query_highest_modseq(&mut self, folder: &str) -> Result<Option<u64>, ConnectionError> {
+        let quoted = format!("\"{:}\"", folder.replace('\\', "\\\\").replace('"', "\\\""));
+        let response = self.run_raw_command(&format!("STATUS {:} (HIGHESTMODSEQ)", quoted))?;
+        let response = String::from_utf8_lossy(&response);
+        self.highest_modseq = response.split("HIGHESTMODSEQ").nth(1)
+            .and_then(|rest| rest.split(|c: char| !c.is_ascii_digit()).find(|digits| !digits.is_empty()))
+            .and_then(|digits| digits.parse().ok());
+        Ok(self.highest_modseq)
+    }
+
+    /// HIGHESTMODSEQ of the selected folder, if looked up since selecting it
+    pub fn highest_modseq(&self) -> Option<u64> {
+        self.highest_modseq
+    }
+
+    /// Capabilities advertised by the server, such as IDLE, SORT or AUTH=PLAIN
+    pub fn capabilities(&mut self) -> Result<&[String], ConnectionError> {
+        if self.capabilities.is_none() {
+            let capabilities = self.session.capabilities()?.iter()
+                .map(|capability| match capability {
+                    Capability::Imap4rev1 => "IMAP4rev1".to_string(),
+                    Capability::Auth(mechanism) => format!("AUTH={:}", mechanism),
+                    Capability::Atom(name) => name.to_string(),
+                })
+                .collect();
+            self.capabilities = Some(capabilities);
+        }
+        Ok(self.capabilities.as_deref().unwrap_or_default())
+    }
+
+    /// Whether the server advertises the capability `name`, such as IDLE or SORT
+    pub fn has_capability(&mut self, name: &str) -> Result<bool, ConnectionError> {
+        Ok(self.capabilities()?.iter().any(|capability| capability.eq_ignore_ascii_case(name)))
+    }
+
+    /// Names of the folders matching the `pattern` below `reference`
+    pub fn list(&mut self, reference: Option<&str>, pattern: Option<&str>) -> Result<ZeroCopy<Vec<Name>>, ConnectionError> {
+        Ok(self.session.list(reference, pattern)?)
+    }
+
+    /// Status `items` of the `folder`, such as `(MESSAGES UNSEEN)`, without selecting it
+    pub fn status(&mut self, folder: &str, items: &str) -> Result<Mailbox, ConnectionError> {
+        Ok(self.session.status(folder, items)?)
+    }
+
+    /// The responses the server sent unasked since the last call, such as the result of `status`
+    pub fn unsolicited_responses(&mut self) -> Vec<UnsolicitedResponse> {
+        self.session.unsolicited_responses.try_iter().collect()
+    }
+
+    /// UIDs of the messages in the selected folder matching the search `query`
+    pub fn uid_search<S: AsRef<str>>(&mut self, query: S) -> Result<HashSet<Uid>, ConnectionError> {
+        Ok(self.session.uid_search(query)?)
+    }
+
+    /// Fetch the `query` items of the messages `uids`, which needs `--write` for items marking them as read
+    pub fn uid_fetch<S1: AsRef<str>, S2: AsRef<str>>(&mut self, uids: S1, query: S2) -> Result<ZeroCopy<Vec<Fetch>>, ConnectionError> {
+        if marks_read(query.as_ref()) {
+            self.check_writable("FETCH without PEEK")?;
+        }
+        Ok(self.session.uid_fetch(uids, query)?)
+    }
+
+    /// Wait for changes of the selected folder with the IDLE extension (RFC 2177)
+    pub fn idle(&mut self) -> Result<Handle<'_, ImapStream>, ConnectionError> {
+        Ok(self.session.idle()?)
+    }
+
+    /// Send a NOOP, which also lets the server report changes of the selected folder
+    pub fn noop(&mut self) -> Result<(), ConnectionError> {
+        Ok(self.session.noop()?)
+    }
+
+    /// Log out and close the connection
+    pub fn logout(&mut self) -> Result<(), ConnectionError> {
+        Ok(self.session.logout()?)
+    }
+
+    /// Fail unless changing messages is permitted, naming the `command` that would change them
+    fn check_writable(&self, command: &str) -> Result<(), ConnectionError> {
+        if self.writable {
+            Ok(())
+        } else {
+            Err(ConfigError(format!("{:} would change messages on \"{:}\", which needs --write", command, self.name)))
+        }
+    }
+
+    /// Change the flags of the messages `uids` as described by `query`, such as `+FLAGS (\\Seen)`
+    pub fn uid_store(&mut self, uids: &str, query: &str) -> Result<ZeroCopy<Vec<Fetch>>, ConnectionError> {
+        self.check_writable("STORE")?;
+        Ok(self.session.uid_store(uids, query)?)
+    }
+
+    /// Copy the messages `uids` to the folder `destination`
+    pub fn uid_copy(&mut self, uids: &str, destination: &str) -> Result<(), ConnectionError> {
+        self.check_writable("COPY")?;
+        Ok(self.session.uid_copy(uids, destination)?)
+    }
+
+    /// Move the messages `uids` to the folder `destination`, with the MOVE extension (RFC 6851)
+    pub fn uid_mv(&mut self, uids: &str, destination: &str) -> Result<(), ConnectionError> {
+        self.check_writable("MOVE")?;
+        Ok(self.session.uid_mv(uids, destination)?)
+    }
+
+    /// Remove the messages flagged as deleted from the selected folder
+    pub fn expunge(&mut self) -> Result<(), ConnectionError> {
+        self.check_writable("EXPUNGE")?;
+        self.session.expunge()?;
+        Ok(())
+    }
+
+    /// Remove those of the messages `uids` flagged as deleted, with the UIDPLUS extension (RFC 4315)
+    pub fn uid_expunge(&mut self, uids: &str) -> Result<Vec<Uid>, ConnectionError> {
+        self.check_writable("EXPUNGE")?;
+        Ok(self.session.uid_expunge(uids)?)
+    }
+
+    /// Upload the raw `message` into `folder` with its `flags` and the internal `date`, now unless given
+    pub fn append(&mut self, folder: &str, message: &[u8], flags: &[Flag], date: Option<DateTime<FixedOffset>>) -> Result<(), ConnectionError> {
+        self.check_writable("APPEND")?;
+        Ok(self.session.append_with_flags_and_date(folder, message, flags, date)?)
+    }
+
+    /// Run a command the imap crate has no typed API for, returning its raw response lines
+    pub fn run_raw_command(&mut self, command: &str) -> Result<Vec<u8>, ConnectionError> {
+        // the IMAP parser does not know the responses of some extensions and reports them
+        // as invalid, but the raw response is still available in the error
+        match self.session.run_command_and_read_response(command) {
+            Ok(response) => Ok(response),
+            Err(Parse(imap::error::ParseError::Invalid(response))) => self.read_rest_of_response(response),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Read the rest of a raw `response` the IMAP parser gave up on at its first unknown line,
+    /// up to the tagged status line, which is left out like in the responses it can parse
+    fn read_rest_of_response(&mut self, mut response: Vec<u8>) -> Result<Vec<u8>, ConnectionError> {
+        let mut line_start = 0;
+        // start of the rest of the current line, which goes on after each literal
+        let mut position = 0;
+        loop {
+            if position == response.len() {
+                self.read_line(&mut response)?;
+            }
+            let end = match response[position..].iter().position(|&byte| byte == b'\n') {
+                Some(index) => position + index + 1,
+                None => {
+                    self.read_line(&mut response)?;
+                    continue;
+                },
+            };
+            if let Some(length) = literal_length(&response[position..end]) {
+                // a literal may contain line breaks of its own, so it is skipped whole
+                while response.len() < end + length {
+                    self.read_line(&mut response)?;
+                }
+                position = end + length;
+                continue;
+            }
+            let line = &response[line_start..end];
+            if !line.starts_with(b"* ") && !line.starts_with(b"+ ") {
+                let line = String::from_utf8_lossy(line).trim_end().to_string();
+                let mut words = line.splitn(3, ' ').skip(1);
+                let (status, information) = (words.next().unwrap_or_default(), words.next().unwrap_or_default().to_string());
+                response.truncate(line_start);
+                return match status.to_ascii_uppercase().as_str() {
+                    "OK" => Ok(response),
+                    "NO" => Err(No(information).into()),
+                    "BAD" => Err(Bad(information).into()),
+                    _ => Err(Parse(imap::error::ParseError::Invalid(line.into_bytes())).into()),
+                };
+            }
+            line_start = end;
+            position = end;
+        }
+    }
+
+    /// Read one more raw line of a response onto `response`
+    fn read_line(&mut self, response: &mut Vec<u8>) -> Result<(), ConnectionError> {
+        // reading the greeting is the only way the imap crate offers to read a single line
+        self.session.greeting_read = false;
+        response.extend(self.session.read_greeting()?);
+        Ok(())
+    }
+}
+
+/// Length of the literal `{n}` ending a response `line`, whose `n` bytes follow the line
+fn literal_length(line: &[u8]) -> Option<usize> {
+    let line = std::str::from_utf8(line).ok()?.trim_end();
+    let (_, length) = line.strip_suffix('}')?.rsplit_once('{')?;
+    length.parse().ok()
+}
+
+/// Whether fetching the items of `query` sets the `\Seen` flag, as the BODY[] and RFC822 items do
+fn marks_read(query: &str) -> bool {
+    let query = query.to_ascii_uppercase();
+    query.contains("BODY[") || query.split(|c: char| c == '(' || c == ')' || c.is_whitespace())
+        .any(|item| item == "RFC822" || item == "RFC822.TEXT")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fetching_bodies_without_peek_marks_messages_read() {
+        assert!(marks_read("BODY[]"));
+        assert!(marks_read("(FLAGS RFC822)"));
+        assert!(!marks_read("(FLAGS INTERNALDATE BODY.PEEK[])"));
+        assert!(!marks_read("(UID RFC822.SIZE RFC822.HEADER)"));
+    }
+}
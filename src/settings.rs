@@ -1,25 +1,56 @@
+use std::collections::BTreeMap;
 use std::net::{SocketAddr, ToSocketAddrs};
-use std::{io, vec};
+use std::{fmt, fs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use std::{env, io, vec};
 
-use config::{Config, ConfigError, Environment, File};
+use config::{Config, ConfigError, Environment, File, Value};
 use directories::ProjectDirs;
+use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
 
+/// Environment variable overriding the location of the configuration file
+pub const CONFIG_PATH_VARIABLE: &str = "POSTKAST_CONFIG";
+
+/// Prefix of the environment variables overriding server credentials
+///
+/// The full variable name is `POSTKAST_SERVERS__<server>__CREDENTIALS__<field>`, where `<server>`
+/// is either the index of the server in the configuration file or its name.
+pub const CREDENTIALS_VARIABLE_PREFIX: &str = "POSTKAST_SERVERS__";
+
+/// Extensions of the configuration file formats looked for in the preference directory, in order
+pub const CONFIG_FILE_EXTENSIONS: [&str; 3] = ["toml", "yaml", "json"];
+
+/// Version of the configuration schema written by this build, see `Settings::migrate` for the upgrade path
+pub const CONFIG_VERSION: u32 = 2;
+
 /// Default server name
-pub const DEFAULT_SERVER_NAME: &'static str = "default";
+pub const DEFAULT_SERVER_NAME: &str = "default";
 
 /// Default server host
-pub const DEFAULT_SERVER_HOST: &'static str = "localhost";
+pub const DEFAULT_SERVER_HOST: &str = "localhost";
 
 /// Default server port number for SMTP protocol
-pub const _DEFAULT_SMTP_PORT: u16 = 25;
+pub const DEFAULT_SMTP_PORT: u16 = 25;
 
 /// Default server port number for POP3 protocol
-pub const _DEFAULT_POP3_PORT: u16 = 110;
+pub const DEFAULT_POP3_PORT: u16 = 110;
 
 /// Default server port number for IMAP protocol
 pub const DEFAULT_IMAP_PORT: u16 = 143;
 
+/// Default mailbox folder to list
+pub const DEFAULT_FOLDER: &str = "INBOX";
+
+/// Default number of seconds to wait for an unresponsive server
+pub const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+/// Default number of seconds between NOOP commands keeping a long IMAP session alive,
+/// well below the 30 minutes servers have to wait before logging out idle clients (RFC 3501)
+pub const DEFAULT_KEEPALIVE_SECS: u64 = 300;
+
 /// Default server port number for SMTP protocol over secure (TLS) channel
 pub const _DEFAULT_SMTP_TLS_PORT: u16 = 465;
 
@@ -30,90 +61,467 @@ pub const _DEFAULT_POP3_TLS_PORT: u16 = 995;
 pub const _DEFAULT_IMAP_TLS_PORT: u16 = 993;
 
 /// Application settings configuration
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct Settings {
+    /// Version of the configuration schema, older files are upgraded when loaded.
+    /// Files without a version are from before versioning.
+    #[serde(default)]
+    version: u32,
+    /// Credentials by name, for servers to refer to with `credentials = "name"` instead of repeating them
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    credentials: BTreeMap<String, Credentials>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     servers: Vec<Server>,
 }
 
 /// Configuration parameters of a server
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct Server {
     name: String,
-    imap: Imap,
+    /// Disabled servers are skipped, unless named with `--server` or included with `--all`
+    #[serde(default = "default_enabled", skip_serializing_if = "is_true")]
+    enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    imap: Option<Imap>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pop3: Option<Pop3>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    smtp: Option<Smtp>,
+    /// SOCKS5 proxy to connect to the IMAP and POP3 servers through instead of connecting directly,
+    /// sending mail with SMTP fails when it is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    proxy: Option<Proxy>,
+    /// Omitted credentials can still be supplied through the environment
+    #[serde(default, skip_serializing_if = "Credentials::is_none")]
     credentials: Credentials,
+    /// Folder to use when none is given with `--folder`
+    #[serde(default = "default_folder", skip_serializing_if = "is_default_folder")]
+    default_folder: String,
+}
+
+/// Mechanism to authenticate to the IMAP server with
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum AuthMechanism {
+    /// The plain IMAP LOGIN command
+    #[serde(rename = "LOGIN")]
+    Login,
+    #[serde(rename = "PLAIN")]
+    Plain,
+    /// Challenge-response that does not send the password itself
+    #[serde(rename = "CRAM-MD5")]
+    CramMd5,
+    #[serde(rename = "XOAUTH2")]
+    XOAuth2,
+}
+
+/// SOCKS5 proxy configuration, with optional username and password authentication
+#[derive(PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Proxy {
+    host: String,
+    port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
 }
 
 /// TLS configuration
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct Tls {
     port: u16,
+    /// PEM file of an additional root certificate, for servers using a private CA
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ca_cert: Option<PathBuf>,
+    /// Accept any server certificate, even expired, self-signed or for another host.
+    /// This makes the connection open to interception, so only use it for testing.
+    #[serde(default, skip_serializing_if = "is_false")]
+    danger_accept_invalid_certs: bool,
 }
 
 /// Configuration of an SMTP server connection settings
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct Smtp {
     host: String,
     port: u16,
     tls: Option<Tls>,
+    /// Upgrade the plaintext connection on `port` to TLS with the STARTTLS command.
+    /// Mutually exclusive with `tls`, which connects to the TLS port directly.
+    #[serde(default)]
+    starttls: bool,
 }
 
 /// Configuration of an IMAP server connection settings
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct Imap {
     host: String,
     port: u16,
     tls: Option<Tls>,
+    /// Upgrade the plaintext connection on `port` to TLS with the STARTTLS command.
+    /// Mutually exclusive with `tls`, which connects to the TLS port directly.
+    #[serde(default)]
+    starttls: bool,
+    /// Server name to request with SNI and verify the certificate against, when connecting
+    /// to `host` by an IP address or alias the certificate is not issued for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tls_domain: Option<String>,
+    /// Seconds to wait for the server to accept the connection or answer a request
+    #[serde(default = "default_timeout_secs")]
+    timeout_secs: u64,
+    /// Seconds between NOOP commands keeping the session alive during long operations
+    #[serde(default = "default_keepalive_secs")]
+    keepalive_secs: u64,
+    /// Compress the session with COMPRESS=DEFLATE when the server supports it, saving bandwidth on large fetches
+    #[serde(default)]
+    compress: bool,
+    /// Connect in plaintext when neither `tls` nor `starttls` is configured, for local test servers.
+    /// The password and messages are then sent unencrypted.
+    #[serde(default)]
+    allow_insecure: bool,
+    /// Addresses of `host` to connect to: auto for all of them, or only ipv4 or ipv6 ones
+    /// when the network cannot reach the others
+    #[serde(default)]
+    address_family: AddressFamily,
+    /// SASL mechanism to authenticate with instead of the IMAP LOGIN command,
+    /// or XOAUTH2 for OAuth2 credentials
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    auth_mechanism: Option<AuthMechanism>,
 }
 
-/// Configuration of an IMAP server connection settings
-#[derive(Debug, Serialize, Deserialize)]
+/// Family of the resolved server addresses to connect to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressFamily {
+    /// Every address, in the order they were resolved
+    #[default]
+    Auto,
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    /// Whether to connect to the `address`
+    pub fn includes(self, address: &SocketAddr) -> bool {
+        match self {
+            AddressFamily::Auto => true,
+            AddressFamily::Ipv4 => address.is_ipv4(),
+            AddressFamily::Ipv6 => address.is_ipv6(),
+        }
+    }
+}
+
+/// Configuration of a POP3 server connection settings
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Pop3 {
+    host: String,
+    port: u16,
+    tls: Option<Tls>,
+    /// Seconds to wait for the server to accept the connection or answer a request
+    #[serde(default = "default_timeout_secs")]
+    timeout_secs: u64,
+}
+
+/// Move the `key` of each server into its `table`, warning that the key is deprecated where it was.
+/// Servers without the table cannot use the key, so it is dropped from them.
+fn move_server_key(cfg: &mut Config, key: &str, table: &str) -> Result<(), ConfigError> {
+    let servers = match cfg.get::<Vec<Value>>("servers") {
+        Ok(servers) => servers,
+        Err(ConfigError::NotFound(_)) => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    let mut moved = false;
+    let mut upgraded = Vec::new();
+    for server in servers {
+        let mut server = server.into_table()?;
+        if let Some(value) = server.remove(key) {
+            let name = server.get("name").and_then(|name| name.clone().into_str().ok()).unwrap_or_default();
+            match server.remove(table) {
+                Some(section) => {
+                    warn!("Server '{:}': {:} is deprecated here, move it into the {:} table", name, key, table);
+                    let mut section = section.into_table()?;
+                    section.entry(key.to_string()).or_insert(value);
+                    server.insert(table.to_string(), section.into());
+                },
+                None => warn!("Server '{:}': {:} is deprecated here and ignored without {:}", name, key, table),
+            }
+            moved = true;
+        }
+        upgraded.push(Value::from(server));
+    }
+    if moved {
+        cfg.set("servers", upgraded)?;
+    }
+    Ok(())
+}
+
+fn default_timeout_secs() -> u64 {
+    DEFAULT_TIMEOUT_SECS
+}
+
+fn default_keepalive_secs() -> u64 {
+    DEFAULT_KEEPALIVE_SECS
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+fn is_true(value: &bool) -> bool {
+    *value
+}
+
+fn default_folder() -> String {
+    DEFAULT_FOLDER.to_string()
+}
+
+fn is_default_folder(folder: &String) -> bool {
+    folder == DEFAULT_FOLDER
+}
+
+/// Credentials used to authenticate with the server
+///
+/// The `Debug` output redacts passwords and tokens, so that they do not leak into logs.
+#[derive(Default, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum Credentials {
+    #[default]
     None,
     UsernameAndPassword{
         username: String,
         password: String,
     },
+    OAuth2 {
+        username: String,
+        access_token: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        refresh_token: Option<String>,
+    },
+    /// Password is looked up from the system keyring by `service` and `username`
+    Keyring {
+        username: String,
+        service: String,
+    },
+    /// Password is the output of running `password_command` with the shell, e.g. `pass show email/work`
+    PasswordCommand {
+        username: String,
+        password_command: String,
+    },
+    /// Name of credentials defined at the top level, replaced with them when the settings are loaded
+    Reference(String),
+}
+
+impl AuthMechanism {
+    /// Name of the mechanism, as in the AUTHENTICATE command
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuthMechanism::Login => "LOGIN",
+            AuthMechanism::Plain => "PLAIN",
+            AuthMechanism::CramMd5 => "CRAM-MD5",
+            AuthMechanism::XOAuth2 => "XOAUTH2",
+        }
+    }
 }
 
 impl Settings {
-    pub fn load() -> Result<Self, ConfigError> {
+    /// Load settings from the given configuration file.
+    ///
+    /// When no `path` is given, the `POSTKAST_CONFIG` environment variable is consulted
+    /// and, failing that, the `Settings.toml`, `Settings.yaml` or `Settings.json` file in
+    /// the user's preference directory. The format of the file follows its extension.
+    /// An explicitly requested file must exist, while the default one is optional.
+    pub fn load_from(path: Option<PathBuf>) -> Result<Self, ConfigError> {
         let mut cfg = Config::new();
 
+        let explicit_path = path.or_else(|| env::var_os(CONFIG_PATH_VARIABLE).map(PathBuf::from));
+        let (config_file, required) = match explicit_path {
+            Some(path) if !path.exists() => return Err(
+                ConfigError::Message(format!("Configuration file {:?} does not exist", path))
+            ),
+            Some(path) => (path, true),
+            None => (Settings::find_config_file()?, false),
+        };
+
+        debug!("Loading settings from {:?}", &config_file);
+        cfg.merge(File::from(config_file).required(required))?;
+
+        cfg.merge(Environment::with_prefix("POSTKAST_"))?;
+        Settings::migrate(&mut cfg)?;
+
+        let mut settings: Settings = cfg.try_into()?;
+        settings.resolve_credentials()?;
+        settings.apply_credential_variables(env::vars());
+        Ok(settings)
+    }
+
+    /// Upgrade a configuration of an older schema version to `CONFIG_VERSION`, one version at a time,
+    /// so that each step only deals with the change of its own version. A step renaming or moving a key
+    /// moves its value and warns that the old key is deprecated, and a schema change older files would
+    /// be misread with needs a new version and step here.
+    ///
+    /// Configurations of a newer version than this build knows are refused rather than misread.
+    fn migrate(cfg: &mut Config) -> Result<(), ConfigError> {
+        let version = match cfg.get::<u32>("version") {
+            Ok(version) => version,
+            Err(ConfigError::NotFound(_)) => 0,
+            Err(err) => return Err(err),
+        };
+        if version > CONFIG_VERSION {
+            return Err(ConfigError::Message(format!(
+                "Configuration version {:} is newer than version {:} supported by this postkast, please upgrade",
+                version, CONFIG_VERSION)));
+        }
+        for from in version..CONFIG_VERSION {
+            debug!("Upgrading configuration version {:} to {:}", from, from + 1);
+            match from {
+                // files from before versioning have the same schema as version 1
+                0 => {},
+                // version 2 moved `auth_mechanism` of a server into its `imap` table, as only IMAP uses it
+                1 => move_server_key(cfg, "auth_mechanism", "imap")?,
+                _ => unreachable!("No upgrade from configuration version {:}", from),
+            }
+        }
+        cfg.set("version", CONFIG_VERSION as i64)?;
+        Ok(())
+    }
+
+    /// Replace the references of servers to named credentials with the credentials they name
+    fn resolve_credentials(&mut self) -> Result<(), ConfigError> {
+        for server in &mut self.servers {
+            if let Credentials::Reference(name) = &server.credentials {
+                let credentials = self.credentials.get(name)
+                    .filter(|credentials| !matches!(credentials, Credentials::Reference(_)))
+                    .ok_or_else(|| ConfigError::Message(format!("Server '{:}' refers to undefined credentials '{:}'", server.name, name)))?;
+                server.credentials = credentials.clone();
+            }
+        }
+        Ok(())
+    }
+
+    /// Override server credentials from `POSTKAST_SERVERS__<server>__CREDENTIALS__<field>` variables.
+    ///
+    /// The `config` environment source cannot address elements of the `servers` array,
+    /// so these variables are resolved here, after the configuration has been deserialized.
+    fn apply_credential_variables<I>(&mut self, vars: I)
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        for (key, value) in vars {
+            let key = key.to_uppercase();
+            let parts: Vec<&str> = match key.strip_prefix(CREDENTIALS_VARIABLE_PREFIX) {
+                Some(rest) => rest.split("__").collect(),
+                None => continue,
+            };
+            let (selector, field) = match parts.as_slice() {
+                [selector, "CREDENTIALS", field] => (*selector, *field),
+                _ => {
+                    warn!("Ignoring unrecognized environment variable {}", key);
+                    continue;
+                }
+            };
+
+            let server = match selector.parse::<usize>() {
+                Ok(index) => self.servers.get_mut(index),
+                Err(_) => self.servers.iter_mut().find(|server| server.name.eq_ignore_ascii_case(selector)),
+            };
+            match server {
+                Some(server) => server.credentials.set_field(field, value),
+                None => warn!("Ignoring {}: no server '{}' is configured", key, selector),
+            }
+        }
+    }
+
+    /// Location of the configuration file in the user's preference directory
+    pub fn default_config_file() -> Result<PathBuf, ConfigError> {
         let project_dirs = ProjectDirs::from("org", "postkast", "Postkast").ok_or(
             ConfigError::Message("Cannot locate project directories".to_string()),
         )?;
 
-        let config_file = project_dirs.preference_dir().join("Settings.toml");
-        println!("Loading settings from {:?}", &config_file);
-        cfg.merge(File::from(config_file).required(false))?;
-
-        cfg.merge(Environment::with_prefix("POSTKAST_"))?;
+        Ok(project_dirs.preference_dir().join("Settings.toml"))
+    }
 
-        cfg.try_into()
+    /// The first existing configuration file in the user's preference directory,
+    /// in the order of `CONFIG_FILE_EXTENSIONS`, or the default one if none exists
+    fn find_config_file() -> Result<PathBuf, ConfigError> {
+        let default_file = Settings::default_config_file()?;
+        let existing = CONFIG_FILE_EXTENSIONS.iter()
+            .map(|extension| default_file.with_extension(extension))
+            .find(|path| path.exists());
+        Ok(existing.unwrap_or(default_file))
     }
 
     pub fn print_default() -> Result<(), ConfigError> {
+        println!("{:}", Settings::default_contents()?);
+        Ok(())
+    }
+
+    /// JSON Schema of the configuration file, for validating and completing it in editors
+    pub fn json_schema() -> String {
+        let schema = schemars::schema_for!(Settings);
+        serde_json::to_string_pretty(&schema).expect("Schemas are always serializable")
+    }
+
+    /// Write the sample configuration to `path`, creating its directory if missing.
+    ///
+    /// An existing file is only overwritten when `force` is set.
+    pub fn write_default(path: &Path, force: bool) -> Result<(), ConfigError> {
+        if path.exists() && !force {
+            return Err(ConfigError::Message(
+                format!("Configuration file {:?} already exists, use --force to overwrite it", path)
+            ));
+        }
+
+        let contents = Settings::default_contents()?;
+        let write_error = |err: io::Error| ConfigError::Message(format!("Cannot write {:?}: {:}", path, err));
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(write_error)?;
+        }
+        fs::write(path, contents).map_err(write_error)
+    }
+
+    /// Sample configuration in TOML format
+    fn default_contents() -> Result<String, ConfigError> {
         let mut default_server = Server::default();
         default_server.with_name(DEFAULT_SERVER_NAME)
             .with_imap_host_and_tls_port("imap.google.com", 993)
+            .with_smtp_host_and_tls_port("smtp.google.com", 465)
             .with_username_and_password("username", "password");
         let default_server = default_server;
-        let default_settings = Settings { servers: vec![ default_server ]};
+        let default_settings = Settings { version: CONFIG_VERSION, credentials: BTreeMap::new(), servers: vec![ default_server ]};
 
         let value = toml::Value::try_from(&default_settings).map_err(|err|
             ConfigError::Message(format!("Cannot convert default settings to TOML: {:?}", err))
         )?;
 
-        let contents = value.to_string();
-        println!("{:}", contents);
-
-        Ok(())
+        Ok(value.to_string())
     }
 }
 
 impl Settings {
+    /// Check the configured servers for problems that would only surface at connect time.
+    ///
+    /// Every problem found is reported, not just the first one.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let problems: Vec<String> = self.servers.iter().enumerate()
+            .flat_map(|(index, server)| server.problems(index))
+            .collect();
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    /// Use the `credentials` for every server instead of the configured ones
+    pub fn override_credentials(&mut self, credentials: &Credentials) {
+        for server in &mut self.servers {
+            server.credentials = credentials.clone();
+        }
+    }
+
     /// Iterator over all configured server configurations
     pub fn servers(&self) -> impl Iterator<Item = &Server> + '_ {
         self.servers.iter()
@@ -125,8 +533,13 @@ impl Default for Server {
     fn default() -> Server {
         Server {
             name: DEFAULT_SERVER_NAME.to_string(),
-            imap: Imap::default(),
+            enabled: true,
+            imap: Some(Imap::default()),
+            pop3: None,
+            smtp: None,
+            proxy: None,
             credentials: Credentials::None,
+            default_folder: DEFAULT_FOLDER.to_string(),
         }
     }
 }
@@ -137,9 +550,34 @@ impl Server {
         &self.name
     }
 
-    /// IMAP server configuration settings
-    pub fn imap(&self) -> &Imap {
-        &self.imap
+    /// Whether the server is used when no server is named explicitly
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// IMAP server configuration settings, if the server is accessed over IMAP
+    pub fn imap(&self) -> Option<&Imap> {
+        self.imap.as_ref()
+    }
+
+    /// POP3 server configuration settings, if the server is accessed over POP3
+    pub fn pop3(&self) -> Option<&Pop3> {
+        self.pop3.as_ref()
+    }
+
+    /// SMTP server configuration settings, if sending mail is configured
+    pub fn smtp(&self) -> Option<&Smtp> {
+        self.smtp.as_ref()
+    }
+
+    /// SOCKS5 proxy to connect through, if any
+    pub fn proxy(&self) -> Option<&Proxy> {
+        self.proxy.as_ref()
+    }
+
+    /// Folder to use when none is given on the command line
+    pub fn default_folder(&self) -> &str {
+        &self.default_folder
     }
 
     /// Server credentials
@@ -148,6 +586,52 @@ impl Server {
     }
 }
 
+impl Server {
+    fn problems(&self, index: usize) -> Vec<String> {
+        let label = if self.name.is_empty() {
+            format!("Server #{:}", index)
+        } else {
+            format!("Server '{:}'", self.name)
+        };
+
+        let mut problems = Vec::new();
+        if self.name.is_empty() {
+            problems.push(format!("{:}: name is empty", label));
+        }
+        if let Some(imap) = &self.imap {
+            if imap.host.is_empty() {
+                problems.push(format!("{:}: IMAP host is empty", label));
+            }
+            if imap.connect_port() == 0 {
+                problems.push(format!("{:}: IMAP port is 0", label));
+            }
+        }
+        if let Some(pop3) = &self.pop3 {
+            if pop3.host.is_empty() {
+                problems.push(format!("{:}: POP3 host is empty", label));
+            }
+            if pop3.port() == 0 {
+                problems.push(format!("{:}: POP3 port is 0", label));
+            }
+        }
+        if self.imap.is_none() && self.pop3.is_none() {
+            problems.push(format!("{:}: neither IMAP nor POP3 is configured", label));
+        }
+        if let Some(smtp) = &self.smtp {
+            if smtp.host.is_empty() {
+                problems.push(format!("{:}: SMTP host is empty", label));
+            }
+            if smtp.port() == 0 {
+                problems.push(format!("{:}: SMTP port is 0", label));
+            }
+        }
+        if let Credentials::None = self.credentials {
+            problems.push(format!("{:}: no credentials configured for login", label));
+        }
+        problems
+    }
+}
+
 impl Server {
     pub fn with_name(&mut self, name: &str) -> &mut Self {
         self.name = name.to_string();
@@ -155,8 +639,18 @@ impl Server {
     }
 
     pub fn with_imap_host_and_tls_port(&mut self, host: &str, port: u16) -> &mut Self {
-        self.imap.host = host.to_string();
-        self.imap.tls = Some(Tls { port });
+        let imap = self.imap.get_or_insert_with(Imap::default);
+        imap.host = host.to_string();
+        imap.tls = Some(Tls::with_port(port));
+        self
+    }
+
+    pub fn with_smtp_host_and_tls_port(&mut self, host: &str, port: u16) -> &mut Self {
+        self.smtp = Some(Smtp {
+            host: host.to_string(),
+            tls: Some(Tls::with_port(port)),
+            ..Smtp::default()
+        });
         self
     }
 
@@ -168,12 +662,67 @@ impl Server {
     }
 }
 
+impl Proxy {
+    /// Proxy hostname
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Proxy port number
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Username and password to authenticate with the proxy, if configured
+    pub fn login(&self) -> Option<(&str, &str)> {
+        match (&self.username, &self.password) {
+            (Some(username), Some(password)) => Some((username, password)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Proxy")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| REDACTED))
+            .finish()
+    }
+}
+
+impl Tls {
+    fn with_port(port: u16) -> Self {
+        Tls { port, ca_cert: None, danger_accept_invalid_certs: false }
+    }
+
+    /// Additional root certificate file to trust
+    pub fn ca_cert(&self) -> Option<&Path> {
+        self.ca_cert.as_deref()
+    }
+
+    /// Whether invalid server certificates are accepted
+    pub fn danger_accept_invalid_certs(&self) -> bool {
+        self.danger_accept_invalid_certs
+    }
+}
+
 impl Default for Imap {
     fn default() -> Self {
         Imap {
             host: DEFAULT_SERVER_HOST.to_string(),
             port: DEFAULT_IMAP_PORT,
             tls: None,
+            starttls: false,
+            tls_domain: None,
+            timeout_secs: DEFAULT_TIMEOUT_SECS,
+            keepalive_secs: DEFAULT_KEEPALIVE_SECS,
+            compress: false,
+            allow_insecure: false,
+            address_family: AddressFamily::Auto,
+            auth_mechanism: None,
         }
     }
 }
@@ -185,7 +734,128 @@ impl Imap {
         &self.host
     }
 
+    /// Plaintext port number, also used when the connection is upgraded with STARTTLS
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Implicit TLS port number, if TLS is configured
+    pub fn tls_port(&self) -> Option<u16> {
+        self.tls().map(|tls| tls.port)
+    }
+
+    /// Port number to connect to: the TLS port when implicit TLS is configured,
+    /// and the plaintext port otherwise
+    pub fn connect_port(&self) -> u16 {
+        self.tls_port().unwrap_or(self.port())
+    }
+
+    /// Server TLS configuration
+    pub fn tls(&self) -> Option<&Tls> {
+        self.tls.as_ref()
+    }
+
+    /// Whether the plaintext connection should be upgraded with STARTTLS
+    pub fn starttls(&self) -> bool {
+        self.starttls
+    }
+
+    /// Server name to verify the TLS certificate against, the hostname unless configured otherwise
+    pub fn tls_domain(&self) -> &str {
+        self.tls_domain.as_deref().unwrap_or(&self.host)
+    }
+
+    /// Time to wait for the server to accept the connection or answer a request
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    /// Time between NOOP commands keeping the session alive during long operations
+    pub fn keepalive(&self) -> Duration {
+        Duration::from_secs(self.keepalive_secs)
+    }
+
+    /// Whether to compress the session when the server supports it
+    pub fn compress(&self) -> bool {
+        self.compress
+    }
+
+    /// Whether connecting without TLS is allowed when none is configured
+    pub fn allow_insecure(&self) -> bool {
+        self.allow_insecure
+    }
+
+    /// Configured authentication mechanism, if other than the default for the credentials
+    pub fn auth_mechanism(&self) -> Option<AuthMechanism> {
+        self.auth_mechanism
+    }
+
+    /// Family of the addresses of the host to connect to
+    pub fn address_family(&self) -> AddressFamily {
+        self.address_family
+    }
+}
+
+impl Default for Pop3 {
+    fn default() -> Self {
+        Pop3 {
+            host: DEFAULT_SERVER_HOST.to_string(),
+            port: DEFAULT_POP3_PORT,
+            tls: None,
+            timeout_secs: DEFAULT_TIMEOUT_SECS,
+        }
+    }
+}
+
+// Public accessors
+impl Pop3 {
+    /// Server hostname
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Server port number, the TLS port when TLS is configured
+    pub fn port(&self) -> u16 {
+        if let Some(tls) = self.tls() {
+            tls.port
+        } else {
+            self.port
+        }
+    }
+
+    /// Server TLS configuration
+    pub fn tls(&self) -> Option<&Tls> {
+        self.tls.as_ref()
+    }
+
+    /// Time to wait for the server to accept the connection or answer a request
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+impl Default for Smtp {
+    fn default() -> Self {
+        Smtp {
+            host: DEFAULT_SERVER_HOST.to_string(),
+            port: DEFAULT_SMTP_PORT,
+            tls: None,
+            starttls: false,
+        }
+    }
+}
+
+// Public accessors
+impl Smtp {
+    /// Server hostname
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
     /// Server port number
+    ///
+    /// This is the TLS port when implicit TLS is configured, and the plaintext port
+    /// otherwise (including when the connection is upgraded with STARTTLS).
     pub fn port(&self) -> u16 {
         if let Some(tls) = self.tls() {
             tls.port
@@ -198,19 +868,497 @@ impl Imap {
     pub fn tls(&self) -> Option<&Tls> {
         self.tls.as_ref()
     }
+
+    /// Whether the plaintext connection should be upgraded with STARTTLS
+    pub fn starttls(&self) -> bool {
+        self.starttls
+    }
+}
+
+/// Placeholder of secrets in debug output
+const REDACTED: &str = "***";
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credentials::None => f.write_str("None"),
+            Credentials::UsernameAndPassword { username, .. } => f.debug_struct("UsernameAndPassword")
+                .field("username", username)
+                .field("password", &REDACTED)
+                .finish(),
+            Credentials::OAuth2 { username, refresh_token, .. } => f.debug_struct("OAuth2")
+                .field("username", username)
+                .field("access_token", &REDACTED)
+                .field("refresh_token", &refresh_token.as_ref().map(|_| REDACTED))
+                .finish(),
+            Credentials::Keyring { username, service } => f.debug_struct("Keyring")
+                .field("username", username)
+                .field("service", service)
+                .finish(),
+            Credentials::PasswordCommand { username, password_command } => f.debug_struct("PasswordCommand")
+                .field("username", username)
+                .field("password_command", password_command)
+                .finish(),
+            Credentials::Reference(name) => f.debug_tuple("Reference").field(name).finish(),
+        }
+    }
+}
+
+impl Credentials {
+    /// Whether no credentials are configured; TOML has no way to write these down
+    fn is_none(&self) -> bool {
+        *self == Credentials::None
+    }
+
+    /// Username of the credentials, if any
+    pub fn username(&self) -> Option<&str> {
+        match self {
+            Credentials::None | Credentials::Reference(_) => None,
+            Credentials::UsernameAndPassword { username, .. }
+            | Credentials::OAuth2 { username, .. }
+            | Credentials::Keyring { username, .. }
+            | Credentials::PasswordCommand { username, .. } => Some(username),
+        }
+    }
+
+    /// Override a single credential field by its upper case environment variable name.
+    ///
+    /// Setting a password turns any credentials into `UsernameAndPassword` and setting
+    /// an access token turns them into `OAuth2`, keeping the configured username.
+    fn set_field(&mut self, field: &str, value: String) {
+        match (field, &mut *self) {
+            ("USERNAME", Credentials::None) => {
+                *self = Credentials::UsernameAndPassword { username: value, password: String::new() };
+            }
+            ("USERNAME", Credentials::UsernameAndPassword { username, .. })
+            | ("USERNAME", Credentials::OAuth2 { username, .. })
+            | ("USERNAME", Credentials::Keyring { username, .. })
+            | ("USERNAME", Credentials::PasswordCommand { username, .. }) => *username = value,
+            ("PASSWORD", Credentials::UsernameAndPassword { password, .. }) => *password = value,
+            ("PASSWORD", _) => {
+                let username = self.username().unwrap_or_default().to_string();
+                *self = Credentials::UsernameAndPassword { username, password: value };
+            }
+            ("ACCESS_TOKEN", Credentials::OAuth2 { access_token, .. }) => *access_token = value,
+            ("ACCESS_TOKEN", _) => {
+                let username = self.username().unwrap_or_default().to_string();
+                *self = Credentials::OAuth2 { username, access_token: value, refresh_token: None };
+            }
+            ("REFRESH_TOKEN", Credentials::OAuth2 { refresh_token, .. }) => *refresh_token = Some(value),
+            (field, _) => warn!("Ignoring unsupported credentials field {}", field),
+        }
+    }
+}
+
+impl FromStr for Credentials {
+    type Err = String;
+
+    /// Parse `username:password`, splitting at the first colon so that the password can contain colons
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((username, password)) if !username.is_empty() => Ok(Credentials::UsernameAndPassword {
+                username: username.to_string(),
+                password: password.to_string(),
+            }),
+            _ => Err("Expected credentials as 'username:password'".to_string()),
+        }
+    }
 }
 
 impl ToSocketAddrs for Imap {
     type Iter = vec::IntoIter<SocketAddr>;
 
     fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
-        let tuple = (self.host(), self.port());
+        let tuple = (self.host(), self.connect_port());
         tuple.to_socket_addrs()
     }
 }
 
-impl Default for Credentials {
-    fn default() -> Self {
-        Credentials::None
+#[cfg(test)]
+mod tests {
+    use config::FileFormat;
+
+    use super::*;
+
+    const SETTINGS: &str = r#"
+        [[servers]]
+        name = "work"
+        credentials = { username = "alice", password = "from-file" }
+
+        [servers.imap]
+        host = "imap.example.com"
+        port = 143
+
+        [[servers]]
+        name = "Personal"
+        credentials = { username = "bob", service = "postkast" }
+
+        [servers.imap]
+        host = "imap.example.org"
+        port = 143
+    "#;
+
+    fn settings() -> Settings {
+        toml::from_str(SETTINGS).unwrap()
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    fn password(server: &Server) -> Option<&str> {
+        match &server.credentials {
+            Credentials::UsernameAndPassword { password, .. } => Some(password),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn password_is_overridden_by_server_index() {
+        let mut settings = settings();
+        settings.apply_credential_variables(vars(&[
+            ("POSTKAST_SERVERS__0__CREDENTIALS__PASSWORD", "from-env"),
+        ]));
+
+        assert_eq!(password(&settings.servers[0]), Some("from-env"));
+        assert_eq!(settings.servers[0].credentials.username(), Some("alice"));
+    }
+
+    #[test]
+    fn password_is_overridden_by_case_insensitive_server_name() {
+        let mut settings = settings();
+        settings.apply_credential_variables(vars(&[
+            ("POSTKAST_SERVERS__PERSONAL__CREDENTIALS__PASSWORD", "from-env"),
+        ]));
+
+        assert_eq!(password(&settings.servers[0]), Some("from-file"));
+        assert_eq!(password(&settings.servers[1]), Some("from-env"));
+        assert_eq!(settings.servers[1].credentials.username(), Some("bob"));
+    }
+
+    #[test]
+    fn credentials_can_be_supplied_purely_through_environment() {
+        let mut settings: Settings = toml::from_str(r#"
+            [[servers]]
+            name = "ci"
+
+            [servers.imap]
+            host = "imap.example.com"
+            port = 143
+        "#).unwrap();
+        settings.apply_credential_variables(vars(&[
+            ("POSTKAST_SERVERS__CI__CREDENTIALS__PASSWORD", "secret"),
+            ("POSTKAST_SERVERS__CI__CREDENTIALS__USERNAME", "ci-bot"),
+        ]));
+
+        assert_eq!(password(&settings.servers[0]), Some("secret"));
+        assert_eq!(settings.servers[0].credentials.username(), Some("ci-bot"));
+    }
+
+    #[test]
+    fn unrelated_and_unknown_variables_are_ignored() {
+        let mut settings = settings();
+        settings.apply_credential_variables(vars(&[
+            ("POSTKAST_CONFIG", "/tmp/Settings.toml"),
+            ("POSTKAST_SERVERS__7__CREDENTIALS__PASSWORD", "nobody"),
+            ("POSTKAST_SERVERS__0__IMAP__HOST", "elsewhere"),
+        ]));
+
+        assert_eq!(password(&settings.servers[0]), Some("from-file"));
+        assert_eq!(settings.servers[0].imap().map(Imap::host), Some("imap.example.com"));
+    }
+
+    fn parse(contents: &str, format: FileFormat) -> Settings {
+        let mut cfg = Config::new();
+        cfg.merge(File::from_str(contents, format)).unwrap();
+        cfg.try_into().unwrap()
+    }
+
+    #[test]
+    fn same_server_is_read_from_toml_yaml_and_json() {
+        let toml = parse(r#"
+            [[servers]]
+            name = "work"
+            credentials = { username = "alice", password = "secret" }
+
+            [servers.imap]
+            host = "imap.example.com"
+            port = 143
+            tls = { port = 993 }
+        "#, FileFormat::Toml);
+        let yaml = parse(r#"
+servers:
+  - name: work
+    credentials:
+      username: alice
+      password: secret
+    imap:
+      host: imap.example.com
+      port: 143
+      tls:
+        port: 993
+"#, FileFormat::Yaml);
+        let json = parse(r#"{
+            "servers": [{
+                "name": "work",
+                "credentials": { "username": "alice", "password": "secret" },
+                "imap": { "host": "imap.example.com", "port": 143, "tls": { "port": 993 } }
+            }]
+        }"#, FileFormat::Json);
+
+        let server = &toml.servers[0];
+        assert_eq!(server.name(), "work");
+        assert_eq!(server.imap().map(Imap::connect_port), Some(993));
+        assert_eq!(password(server), Some("secret"));
+        assert_eq!(password(&yaml.servers[0]), Some("secret"));
+        assert_eq!(password(&json.servers[0]), Some("secret"));
+        assert_eq!(format!("{:?}", yaml), format!("{:?}", toml));
+        assert_eq!(format!("{:?}", json), format!("{:?}", toml));
+    }
+
+    #[test]
+    fn debug_output_redacts_secrets() {
+        let settings = settings();
+        let debug = format!("{:?}", settings);
+        assert!(!debug.contains("from-file"), "password leaked: {:}", debug);
+        assert!(debug.contains(r#"password: "***""#));
+
+        let oauth2 = Credentials::OAuth2 {
+            username: "alice".to_string(),
+            access_token: "access-secret".to_string(),
+            refresh_token: Some("refresh-secret".to_string()),
+        };
+        let debug = format!("{:?}", oauth2);
+        assert!(!debug.contains("secret"), "token leaked: {:}", debug);
+    }
+
+    #[test]
+    fn serialization_keeps_secrets() {
+        let toml = toml::to_string(&settings()).unwrap();
+        assert!(toml.contains("from-file"));
+    }
+
+    #[test]
+    fn validate_reports_problems_of_all_servers() {
+        let settings: Settings = toml::from_str(r#"
+            [[servers]]
+            name = ""
+            credentials = { username = "alice", password = "secret" }
+
+            [servers.imap]
+            host = ""
+            port = 143
+
+            [[servers]]
+            name = "broken"
+
+            [servers.imap]
+            host = "imap.example.com"
+            port = 143
+            tls = { port = 0 }
+        "#).unwrap();
+
+        assert_eq!(settings.validate(), Err(vec![
+            "Server #0: name is empty".to_string(),
+            "Server #0: IMAP host is empty".to_string(),
+            "Server 'broken': IMAP port is 0".to_string(),
+            "Server 'broken': no credentials configured for login".to_string(),
+        ]));
+    }
+
+    #[test]
+    fn validate_accepts_valid_configuration() {
+        assert_eq!(settings().validate(), Ok(()));
+    }
+
+    #[test]
+    fn load_from_applies_process_environment() {
+        let path = env::temp_dir().join(format!("postkast-settings-{}.toml", std::process::id()));
+        std::fs::write(&path, SETTINGS).unwrap();
+        env::set_var("POSTKAST_SERVERS__WORK__CREDENTIALS__PASSWORD", "from-process-env");
+
+        let settings = Settings::load_from(Some(path.clone()));
+
+        env::remove_var("POSTKAST_SERVERS__WORK__CREDENTIALS__PASSWORD");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(password(&settings.unwrap().servers[0]), Some("from-process-env"));
+    }
+
+    fn round_trip(settings: &Settings) -> Settings {
+        // go through a TOML value like the default configuration does, which puts tables last
+        let toml = toml::Value::try_from(settings).unwrap().to_string();
+        toml::from_str(&toml).unwrap_or_else(|err| panic!("{:}\n{:}", err, toml))
+    }
+
+    fn server_with(name: &str, credentials: Credentials) -> Server {
+        Server { name: name.to_string(), credentials, ..Server::default() }
+    }
+
+    #[test]
+    fn settings_round_trip_through_toml_with_all_credentials() {
+        let mut credentials = BTreeMap::new();
+        credentials.insert("shared".to_string(), Credentials::UsernameAndPassword {
+            username: "alice".to_string(),
+            password: "secret".to_string(),
+        });
+        let settings = Settings {
+            version: CONFIG_VERSION,
+            credentials,
+            servers: vec![
+                server_with("none", Credentials::None),
+                server_with("reference", Credentials::Reference("shared".to_string())),
+                server_with("password", Credentials::UsernameAndPassword {
+                    username: "alice".to_string(),
+                    password: "secret".to_string(),
+                }),
+                server_with("oauth2", Credentials::OAuth2 {
+                    username: "alice".to_string(),
+                    access_token: "access".to_string(),
+                    refresh_token: Some("refresh".to_string()),
+                }),
+                server_with("oauth2-without-refresh", Credentials::OAuth2 {
+                    username: "alice".to_string(),
+                    access_token: "access".to_string(),
+                    refresh_token: None,
+                }),
+                server_with("keyring", Credentials::Keyring {
+                    username: "alice".to_string(),
+                    service: "postkast".to_string(),
+                }),
+                server_with("password-command", Credentials::PasswordCommand {
+                    username: "alice".to_string(),
+                    password_command: "pass show email/work".to_string(),
+                }),
+            ],
+        };
+
+        assert_eq!(round_trip(&settings), settings);
+    }
+
+    #[test]
+    fn unversioned_configuration_is_stamped_and_newer_one_refused() {
+        let mut cfg = Config::new();
+        cfg.merge(File::from_str("[[servers]]\nname = \"old\"", FileFormat::Toml)).unwrap();
+        Settings::migrate(&mut cfg).unwrap();
+        let settings: Settings = cfg.try_into().unwrap();
+        assert_eq!(settings.version, CONFIG_VERSION);
+        assert_eq!(settings.servers[0].name(), "old");
+
+        let mut cfg = Config::new();
+        cfg.merge(File::from_str(&format!("version = {:}", CONFIG_VERSION + 1), FileFormat::Toml)).unwrap();
+        assert!(Settings::migrate(&mut cfg).is_err());
+    }
+
+    #[test]
+    fn version_0_configuration_is_upgraded() {
+        let mut cfg = Config::new();
+        cfg.merge(File::from_str(r#"
+            [[servers]]
+            name = "old"
+            credentials = { username = "alice", password = "secret" }
+            auth_mechanism = "CRAM-MD5"
+            imap = { host = "imap.example.com", port = 993, tls = { port = 993 } }
+
+            [[servers]]
+            name = "pop"
+            auth_mechanism = "PLAIN"
+            pop3 = { host = "pop.example.com", port = 995, tls = { port = 995 } }
+        "#, FileFormat::Toml)).unwrap();
+        Settings::migrate(&mut cfg).unwrap();
+        let settings: Settings = cfg.try_into().unwrap();
+        assert_eq!(settings.version, CONFIG_VERSION);
+        assert_eq!(settings.servers[0].imap().and_then(Imap::auth_mechanism), Some(AuthMechanism::CramMd5));
+        assert_eq!(settings.servers[0].imap().map(Imap::host), Some("imap.example.com"));
+        assert!(settings.servers[1].imap().is_none());
+    }
+
+    #[test]
+    fn credentials_are_parsed_from_username_and_password() {
+        assert_eq!("alice:se:cret".parse(), Ok(Credentials::UsernameAndPassword {
+            username: "alice".to_string(),
+            password: "se:cret".to_string(),
+        }));
+        assert!("".parse::<Credentials>().is_err());
+        assert!("alice".parse::<Credentials>().is_err());
+        assert!(":secret".parse::<Credentials>().is_err());
+    }
+
+    #[test]
+    fn servers_share_credentials_by_name() {
+        let mut settings: Settings = toml::from_str(r#"
+            [credentials.work]
+            username = "alice"
+            password = "secret"
+
+            [[servers]]
+            name = "imap"
+            credentials = "work"
+
+            [[servers]]
+            name = "inline"
+            credentials = { username = "bob", password = "hunter2" }
+        "#).unwrap();
+        settings.resolve_credentials().unwrap();
+
+        assert_eq!(settings.servers[0].credentials, settings.credentials["work"]);
+        assert_eq!(password(&settings.servers[1]), Some("hunter2"));
+
+        settings.servers[1].credentials = Credentials::Reference("home".to_string());
+        assert!(settings.resolve_credentials().is_err());
+    }
+
+    #[test]
+    fn settings_round_trip_through_toml_with_all_protocols() {
+        let mut server = Server::default();
+        server.with_name("full")
+            .with_imap_host_and_tls_port("imap.example.com", 993)
+            .with_smtp_host_and_tls_port("smtp.example.com", 465);
+        server.pop3 = Some(Pop3::default());
+        let settings = Settings { version: CONFIG_VERSION, credentials: BTreeMap::new(), servers: vec![server, Server::default()] };
+
+        assert_eq!(round_trip(&settings), settings);
+    }
+
+    #[test]
+    fn addresses_are_filtered_by_the_address_family() {
+        let imap: Imap = toml::from_str(r#"
+            host = "imap.example.com"
+            port = 143
+            address_family = "ipv4"
+        "#).unwrap();
+        let ipv4: SocketAddr = "192.0.2.1:143".parse().unwrap();
+        let ipv6: SocketAddr = "[2001:db8::1]:143".parse().unwrap();
+
+        assert_eq!(imap.address_family(), AddressFamily::Ipv4);
+        assert!(imap.address_family().includes(&ipv4));
+        assert!(!imap.address_family().includes(&ipv6));
+        assert!(AddressFamily::default().includes(&ipv4) && AddressFamily::default().includes(&ipv6));
+    }
+
+    #[test]
+    fn imap_connect_port_is_tls_port_when_tls_is_configured() {
+        let tls: Imap = toml::from_str(r#"
+            host = "imap.example.com"
+            port = 143
+            tls = { port = 993 }
+        "#).unwrap();
+        let plain: Imap = toml::from_str(r#"
+            host = "imap.example.com"
+            port = 143
+        "#).unwrap();
+
+        assert_eq!(tls.port(), 143);
+        assert_eq!(tls.tls_port(), Some(993));
+        assert_eq!(tls.connect_port(), 993);
+        assert_eq!(plain.port(), 143);
+        assert_eq!(plain.tls_port(), None);
+        assert_eq!(plain.connect_port(), 143);
+    }
+
+    #[test]
+    fn default_servers_connect_to_localhost() {
+        assert_eq!(Imap::default().host(), "localhost");
+        assert_eq!(Smtp::default().host(), "localhost");
+        assert_eq!(Pop3::default().host(), "localhost");
     }
 }
@@ -0,0 +1,140 @@
+use std::fs;
+
+use lettre::{Message, SmtpTransport, Transport};
+use lettre::message::{Mailbox, Mailboxes, MessageBuilder};
+use lettre::message::header::To;
+use lettre::transport::smtp::authentication::{Credentials as SmtpCredentials, Mechanism};
+use lettre::transport::smtp::client::{Certificate, Tls, TlsParameters};
+
+use crate::ConnectionError::{self, ConfigError};
+use crate::{command_password, keyring_password};
+use crate::reply::Reply;
+use crate::settings::{Credentials, Server, Smtp};
+
+impl Smtp {
+    /// Build a transport to this SMTP server, authenticating with the given credentials.
+    ///
+    /// The `name` of the server configuration is only used in error messages.
+    pub fn connect(&self, name: &str, credentials: &Credentials) -> Result<SmtpTransport, ConnectionError> {
+        let (username, secret, mechanism) = match credentials {
+            Credentials::UsernameAndPassword { username, password } => (username, password.clone(), Mechanism::Login),
+            Credentials::OAuth2 { username, access_token, .. } => (username, access_token.clone(), Mechanism::Xoauth2),
+            Credentials::Keyring { username, service } => {
+                (username, keyring_password(name, service, username)?, Mechanism::Login)
+            },
+            Credentials::PasswordCommand { username, password_command } => {
+                (username, command_password(name, password_command)?, Mechanism::Login)
+            },
+            Credentials::None | Credentials::Reference(_) => return Err(ConfigError(format!("No username and password configured for '{:?}'", name))),
+        };
+
+        let tls = match (self.tls(), self.starttls()) {
+            (Some(_), false) => Tls::Wrapper(self.tls_parameters()?),
+            (None, true) => Tls::Required(self.tls_parameters()?),
+            (Some(_), true) => return Err(ConfigError(format!("Both TLS and STARTTLS configured for '{:}'", name))),
+            (None, false) => return Err(ConfigError(format!("No TLS configured for '{:}'", name))),
+        };
+
+        Ok(SmtpTransport::builder_dangerous(self.host())
+            .port(self.port())
+            .tls(tls)
+            .credentials(SmtpCredentials::new(username.to_string(), secret))
+            .authentication(vec![mechanism])
+            .build())
+    }
+
+    /// TLS parameters of the connection, trusting the CA certificate and accepting invalid certificates
+    /// as configured, like for IMAP connections
+    fn tls_parameters(&self) -> Result<TlsParameters, ConnectionError> {
+        let mut builder = TlsParameters::builder(self.host().to_string());
+        if let Some(tls) = self.tls() {
+            if let Some(path) = tls.ca_cert() {
+                let pem = fs::read(path)
+                    .map_err(|err| ConfigError(format!("Cannot read CA certificate {:?}: {:}", path, err)))?;
+                let cert = Certificate::from_pem(&pem)
+                    .map_err(|err| ConfigError(format!("Invalid CA certificate {:?}: {:}", path, err)))?;
+                builder = builder.add_root_certificate(cert);
+            }
+            builder = builder.dangerous_accept_invalid_certs(tls.danger_accept_invalid_certs());
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// Send a plain text message from the account of the `server`
+pub fn send_mail(server: &Server, to: &str, subject: &str, body: &str) -> Result<(), ConnectionError> {
+    let message = Message::builder()
+        .to(to.parse::<Mailbox>()?)
+        .subject(subject);
+    send(server, message, body)
+}
+
+/// Send the `reply` from the account of the `server`
+pub fn send_reply(server: &Server, reply: &Reply) -> Result<(), ConnectionError> {
+    let mut message = Message::builder()
+        .mailbox(To::from(reply.to.parse::<Mailboxes>()?))
+        .subject(reply.subject.as_str());
+    if let Some(id) = &reply.in_reply_to {
+        message = message.in_reply_to(id.to_string());
+    }
+    if !reply.references.is_empty() {
+        message = message.references(reply.references.join(" "));
+    }
+    send(server, message, &reply.body)
+}
+
+fn send(server: &Server, message: MessageBuilder, body: &str) -> Result<(), ConnectionError> {
+    let name = server.name();
+    let smtp = server.smtp()
+        .ok_or_else(|| ConfigError(format!("No SMTP configured for '{:}'", name)))?;
+    if server.proxy().is_some() {
+        // the SMTP transport opens its own connections, which would bypass the proxy
+        return Err(ConfigError(format!("Sending through a SOCKS5 proxy is not supported for '{:}'", name)));
+    }
+
+    let from = match server.credentials() {
+        Credentials::UsernameAndPassword { username, .. }
+        | Credentials::OAuth2 { username, .. }
+        | Credentials::Keyring { username, .. }
+        | Credentials::PasswordCommand { username, .. } => username.parse::<Mailbox>()?,
+        Credentials::None | Credentials::Reference(_) => return Err(ConfigError(format!("No username and password configured for '{:?}'", name))),
+    };
+
+    let email = message
+        .from(from)
+        .body(body.to_string())?;
+
+    smtp.connect(name, server.credentials())?.send(&email)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn smtp(tls: &str) -> Smtp {
+        toml::from_str(&format!("host = \"smtp.example.com\"\nport = 465\ntls = {:}", tls)).unwrap()
+    }
+
+    #[test]
+    fn tls_parameters_use_the_configured_ca_certificate() {
+        let err = smtp("{ port = 465, ca_cert = \"/nonexistent/ca.pem\" }").tls_parameters().err().unwrap();
+        assert!(matches!(&err, ConfigError(msg) if msg.starts_with("Cannot read CA certificate")), "unexpected error {:?}", err);
+        assert!(smtp("{ port = 465, danger_accept_invalid_certs = true }").tls_parameters().is_ok());
+    }
+
+    #[test]
+    fn mail_is_not_sent_around_the_proxy() {
+        let server: Server = toml::from_str(r#"
+            name = "proxied"
+            credentials = { username = "alice@example.com", password = "secret" }
+            proxy = { host = "127.0.0.1", port = 1080 }
+            imap = { host = "imap.example.com", port = 993, tls = { port = 993 } }
+            smtp = { host = "smtp.example.com", port = 465, tls = { port = 465 } }
+        "#).unwrap();
+        let err = send_mail(&server, "bob@example.com", "Hello", "Hi").err().unwrap();
+        assert!(matches!(&err, ConfigError(msg) if msg == "Sending through a SOCKS5 proxy is not supported for 'proxied'"),
+            "unexpected error {:?}", err);
+    }
+}
@@ -0,0 +1,156 @@
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const USERNAME_AND_PASSWORD: u8 = 2;
+const CONNECT: u8 = 1;
+
+/// Ask the SOCKS5 proxy (RFC 1928) at the other end of `stream` to connect it to `host` and `port`,
+/// authenticating with the username and password of `login` (RFC 1929) if given.
+///
+/// The host name is sent as it is for the proxy to resolve, so that DNS lookups do not bypass it.
+pub fn handshake<S: Read + Write>(stream: &mut S, host: &str, port: u16, login: Option<(&str, &str)>) -> io::Result<()> {
+    match login {
+        Some(_) => stream.write_all(&[VERSION, 2, NO_AUTHENTICATION, USERNAME_AND_PASSWORD])?,
+        None => stream.write_all(&[VERSION, 1, NO_AUTHENTICATION])?,
+    }
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply)?;
+    check_version(reply[0], VERSION)?;
+    match (reply[1], login) {
+        (NO_AUTHENTICATION, _) => {},
+        (USERNAME_AND_PASSWORD, Some((username, password))) => authenticate(stream, username, password)?,
+        _ => return Err(io::Error::new(io::ErrorKind::PermissionDenied, "The proxy accepts none of the authentication methods")),
+    }
+
+    let mut request = vec![VERSION, CONNECT, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(address)) => {
+            request.push(1);
+            request.extend_from_slice(&address.octets());
+        },
+        Ok(IpAddr::V6(address)) => {
+            request.push(4);
+            request.extend_from_slice(&address.octets());
+        },
+        Err(_) => {
+            let length = u8::try_from(host.len())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("Host name {:} is too long for the proxy", host)))?;
+            request.push(3);
+            request.push(length);
+            request.extend_from_slice(host.as_bytes());
+        },
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request)?;
+    stream.flush()?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply)?;
+    check_version(reply[0], VERSION)?;
+    if reply[1] != 0 {
+        return Err(io::Error::new(io::ErrorKind::ConnectionRefused,
+            format!("The proxy cannot connect to {:}:{:}: {:}", host, port, reply_message(reply[1]))));
+    }
+    // the address the proxy connected from is of no use here, but has to be read
+    let length = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut length = [0; 1];
+            stream.read_exact(&mut length)?;
+            length[0] as usize
+        },
+        other => return Err(invalid_data(format!("Unknown address type {:} in the proxy reply", other))),
+    };
+    let mut address = vec![0; length + 2];
+    stream.read_exact(&mut address)?;
+    Ok(())
+}
+
+/// Username and password authentication (RFC 1929)
+fn authenticate<S: Read + Write>(stream: &mut S, username: &str, password: &str) -> io::Result<()> {
+    let too_long = || io::Error::new(io::ErrorKind::InvalidInput, "The proxy username and password can have 255 bytes at most");
+    let mut request = vec![1, u8::try_from(username.len()).map_err(|_| too_long())?];
+    request.extend_from_slice(username.as_bytes());
+    request.push(u8::try_from(password.len()).map_err(|_| too_long())?);
+    request.extend_from_slice(password.as_bytes());
+    stream.write_all(&request)?;
+    stream.flush()?;
+
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply)?;
+    check_version(reply[0], 1)?;
+    if reply[1] != 0 {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "The proxy rejected the username and password"));
+    }
+    Ok(())
+}
+
+fn check_version(version: u8, expected: u8) -> io::Result<()> {
+    if version == expected {
+        Ok(())
+    } else {
+        Err(invalid_data(format!("Unexpected version {:} in the proxy reply, is it a SOCKS5 proxy?", version)))
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Description of a reply code of a failed CONNECT request
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general SOCKS server failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::test_support::MockStream;
+
+    #[test]
+    fn host_name_is_sent_to_the_proxy() {
+        let mut stream = MockStream::new(&[5, 0, 5, 0, 0, 1, 10, 0, 0, 1, 0x1f, 0x90]);
+        handshake(&mut stream, "imap.example.com", 993, None).unwrap();
+
+        let mut expected = vec![5, 1, 0, 5, 1, 0, 3, 16];
+        expected.extend_from_slice(b"imap.example.com");
+        expected.extend_from_slice(&[0x03, 0xe1]);
+        assert_eq!(stream.output, expected);
+    }
+
+    #[test]
+    fn proxy_login_is_sent_when_asked_for() {
+        let mut stream = MockStream::new(&[5, 2, 1, 0, 5, 0, 0, 1, 10, 0, 0, 1, 0x1f, 0x90]);
+        handshake(&mut stream, "10.0.0.2", 143, Some(("alice", "pw"))).unwrap();
+
+        let mut expected = vec![5, 2, 0, 2, 1, 5];
+        expected.extend_from_slice(b"alice");
+        expected.push(2);
+        expected.extend_from_slice(b"pw");
+        expected.extend_from_slice(&[5, 1, 0, 1, 10, 0, 0, 2, 0, 143]);
+        assert_eq!(stream.output, expected);
+    }
+
+    #[test]
+    fn failures_of_the_proxy_are_reported() {
+        let err = handshake(&mut MockStream::new(&[5, 0xff]), "imap.example.com", 993, None).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        let err = handshake(&mut MockStream::new(&[5, 0, 5, 4, 0, 1]), "imap.example.com", 993, None).err().unwrap();
+        assert_eq!(err.to_string(), "The proxy cannot connect to imap.example.com:993: host unreachable");
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+
+use directories::ProjectDirs;
+use imap::types::Uid;
+use serde::{Deserialize, Serialize};
+
+/// Servers listed concurrently update the state file one at a time
+static STATE_LOCK: Mutex<()> = Mutex::new(());
+
+/// What is known of a folder from previous runs
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FolderState {
+    /// UIDVALIDITY of the folder when last selected, the UIDs seen before are meaningless once it changes
+    pub uid_validity: u32,
+    /// Newest UID listed with `--new-only`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_uid: Option<Uid>,
+    /// HIGHESTMODSEQ of the folder when last listed with `--changed-since` (RFC 7162)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub highest_modseq: Option<u64>,
+}
+
+/// Folder states by server name and folder name, kept in the user's data directory
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    servers: BTreeMap<String, BTreeMap<String, FolderState>>,
+}
+
+/// Location of the state file
+fn state_file() -> io::Result<PathBuf> {
+    let project_dirs = ProjectDirs::from("org", "postkast", "Postkast")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Cannot locate project directories"))?;
+    Ok(project_dirs.data_dir().join("state.json"))
+}
+
+fn load(path: &Path) -> io::Result<State> {
+    match fs::read(path) {
+        Ok(json) => Ok(serde_json::from_slice(&json)?),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(State::default()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Write the `state` to a temporary file first and rename it over the state file at `path`,
+/// so that other processes never read a partly written one
+fn save(path: &Path, state: &State) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let temp = path.with_extension(format!("json.{:}.tmp", process::id()));
+    fs::write(&temp, serde_json::to_vec_pretty(state)?)?;
+    fs::rename(&temp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&temp);
+    })
+}
+
+/// Saved state of `folder` on `server`, if any
+pub fn folder_state(server: &str, folder: &str) -> io::Result<Option<FolderState>> {
+    let _lock = STATE_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    Ok(load(&state_file()?)?.servers.get(server).and_then(|folders| folders.get(folder)).copied())
+}
+
+/// Save the state of `folder` on `server`, or forget it when `None`
+pub fn set_folder_state(server: &str, folder: &str, folder_state: Option<FolderState>) -> io::Result<()> {
+    let _lock = STATE_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    update(&state_file()?, server, folder, folder_state)
+}
+
+fn update(path: &Path, server: &str, folder: &str, folder_state: Option<FolderState>) -> io::Result<()> {
+    let mut state = load(path)?;
+    let folders = state.servers.entry(server.to_string()).or_default();
+    match folder_state {
+        Some(folder_state) => folders.insert(folder.to_string(), folder_state),
+        None => folders.remove(folder),
+    };
+    save(path, &state)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    fn saved(path: &Path, server: &str, folder: &str) -> Option<FolderState> {
+        load(path).unwrap().servers.get(server).and_then(|folders| folders.get(folder)).copied()
+    }
+
+    #[test]
+    fn folder_states_are_saved_and_forgotten() {
+        let dir = env::temp_dir().join(format!("postkast-state-{}", process::id()));
+        let path = dir.join("state.json");
+        let inbox = FolderState { uid_validity: 7, last_uid: Some(42), highest_modseq: None };
+        let sent = FolderState { uid_validity: 8, last_uid: None, highest_modseq: Some(1000) };
+
+        assert_eq!(saved(&path, "mail", "INBOX"), None);
+        update(&path, "mail", "INBOX", Some(inbox)).unwrap();
+        update(&path, "mail", "Sent", Some(sent)).unwrap();
+        assert_eq!(saved(&path, "mail", "INBOX"), Some(inbox));
+        assert_eq!(saved(&path, "mail", "Sent"), Some(sent));
+        assert_eq!(saved(&path, "other", "INBOX"), None);
+
+        update(&path, "mail", "INBOX", None).unwrap();
+        assert_eq!(saved(&path, "mail", "INBOX"), None);
+        assert_eq!(saved(&path, "mail", "Sent"), Some(sent));
+
+        // only the state file is left behind
+        let files: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(files, vec!["state.json"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_state_file_is_an_error() {
+        let path = env::temp_dir().join(format!("postkast-state-{}.json", process::id()));
+        fs::write(&path, "{ not json").unwrap();
+        let result = load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result.err().map(|err| err.kind()), Some(io::ErrorKind::InvalidData));
+    }
+}
@@ -0,0 +1,348 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use imap::Error::{No, TlsHandshake};
+use imap::extensions::idle::SetReadTimeout;
+use native_tls::{TlsConnector, TlsStream};
+
+use crate::ConnectionError::{self, ImapError};
+
+/// Size of the buffers for compressed data
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// Connection to an IMAP server, encrypted unless plaintext was explicitly allowed
+enum Transport {
+    Tls(TlsStream<TcpStream>),
+    Plain(TcpStream),
+}
+
+impl Transport {
+    fn tcp_stream(&self) -> &TcpStream {
+        match self {
+            Transport::Tls(stream) => stream.get_ref(),
+            Transport::Plain(stream) => stream,
+        }
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tls(stream) => stream.read(buf),
+            Transport::Plain(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tls(stream) => stream.write(buf),
+            Transport::Plain(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Tls(stream) => stream.flush(),
+            Transport::Plain(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Stream to an IMAP server, which switches to COMPRESS=DEFLATE (RFC 4978) in both
+/// directions once the server has accepted the COMPRESS command
+pub struct ImapStream {
+    stream: Transport,
+    /// Set by the session when the server has accepted the COMPRESS command
+    compressed: Arc<AtomicBool>,
+    deflate: Option<Deflate>,
+}
+
+/// Raw DEFLATE compression state of a stream
+struct Deflate {
+    compress: Compress,
+    decompress: Decompress,
+    /// Data read from the server, of which `input[start..end]` is not decompressed yet
+    input: Box<[u8]>,
+    start: usize,
+    end: usize,
+}
+
+impl ImapStream {
+    /// Stream over a TLS connection
+    pub fn tls(stream: TlsStream<TcpStream>, compressed: Arc<AtomicBool>) -> Self {
+        ImapStream { stream: Transport::Tls(stream), compressed, deflate: None }
+    }
+
+    /// Stream over an unencrypted connection, which anyone on the way can read and tamper with
+    pub fn plain(stream: TcpStream, compressed: Arc<AtomicBool>) -> Self {
+        ImapStream { stream: Transport::Plain(stream), compressed, deflate: None }
+    }
+
+    /// Whether the data is compressed, setting up the compression when it has just been turned on
+    fn is_compressed(&mut self) -> bool {
+        if self.deflate.is_none() && self.compressed.load(Ordering::SeqCst) {
+            self.deflate = Some(Deflate {
+                compress: Compress::new(Compression::default(), false),
+                decompress: Decompress::new(false),
+                input: vec![0; BUFFER_SIZE].into_boxed_slice(),
+                start: 0,
+                end: 0,
+            });
+        }
+        self.deflate.is_some()
+    }
+
+    /// Compress the `input` and write it out, flushing the compressed data as requested by `flush`
+    fn write_compressed(&mut self, mut input: &[u8], flush: FlushCompress) -> io::Result<()> {
+        let deflate = self.deflate.as_mut().expect("compression is set up");
+        let mut output = Vec::with_capacity(BUFFER_SIZE);
+        loop {
+            output.clear();
+            let total_in = deflate.compress.total_in();
+            deflate.compress.compress_vec(input, &mut output, flush).map_err(io::Error::other)?;
+            input = &input[(deflate.compress.total_in() - total_in) as usize..];
+            self.stream.write_all(&output)?;
+            // a full output buffer may leave more compressed data to write
+            if input.is_empty() && output.len() < output.capacity() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Read for ImapStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.is_compressed() {
+            return self.stream.read(buf);
+        }
+        let deflate = self.deflate.as_mut().expect("compression is set up");
+        loop {
+            let (total_in, total_out) = (deflate.decompress.total_in(), deflate.decompress.total_out());
+            deflate.decompress.decompress(&deflate.input[deflate.start..deflate.end], buf, FlushDecompress::None)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            deflate.start += (deflate.decompress.total_in() - total_in) as usize;
+            let read = (deflate.decompress.total_out() - total_out) as usize;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            if deflate.start == deflate.end {
+                deflate.start = 0;
+                deflate.end = self.stream.read(&mut deflate.input)?;
+                if deflate.end == 0 {
+                    return Ok(0);
+                }
+            }
+        }
+    }
+}
+
+impl Write for ImapStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.is_compressed() {
+            return self.stream.write(buf);
+        }
+        self.write_compressed(buf, FlushCompress::None)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.is_compressed() {
+            // the server can only decompress the commands sent so far after a sync flush
+            self.write_compressed(&[], FlushCompress::Sync)?;
+        }
+        self.stream.flush()
+    }
+}
+
+impl SetReadTimeout for ImapStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> imap::error::Result<()> {
+        self.stream.tcp_stream().set_read_timeout(timeout).map_err(imap::Error::Io)
+    }
+}
+
+/// Read the greeting of the server and upgrade the plaintext connection to TLS with the STARTTLS command.
+/// `Client::secure` of the imap crate does the same, but only for clients of the bare TLS stream.
+pub fn starttls(stream: TcpStream, domain: &str, tls: &TlsConnector) -> Result<TlsStream<TcpStream>, ConnectionError> {
+    let mut reader = BufReader::new(stream);
+    negotiate_starttls(&mut reader)?;
+    // the server waits for the TLS handshake, so nothing is left in the buffer
+    Ok(tls.connect(domain, reader.into_inner()).map_err(TlsHandshake)?)
+}
+
+/// Check the greeting and the capabilities of the server and send the STARTTLS command.
+///
+/// A server greeting with PREAUTH is refused, as the session would be authenticated before
+/// the connection is encrypted, and so is one not advertising STARTTLS.
+fn negotiate_starttls<S: Read + Write>(reader: &mut BufReader<S>) -> Result<(), ConnectionError> {
+    let greeting = read_line(reader)?;
+    let capabilities = if let Some(text) = greeting.strip_prefix("* OK ") {
+        text.strip_prefix("[CAPABILITY ").and_then(|text| text.split_once(']')).map(|(capabilities, _)| capabilities.to_string())
+    } else if greeting.starts_with("* PREAUTH") {
+        return Err(ImapError(No("The server skipped authentication before STARTTLS, refusing to continue unencrypted".to_string())));
+    } else {
+        return Err(ImapError(No(format!("The server refused the connection: {:}", greeting))));
+    };
+    let capabilities = match capabilities {
+        Some(capabilities) => capabilities,
+        None => command(reader, "s1", "CAPABILITY")?.iter()
+            .find_map(|line| line.strip_prefix("* CAPABILITY ").map(str::to_string))
+            .unwrap_or_default(),
+    };
+    if !capabilities.split_whitespace().any(|capability| capability.eq_ignore_ascii_case("STARTTLS")) {
+        return Err(ImapError(No("The server does not support STARTTLS".to_string())));
+    }
+    command(reader, "s2", "STARTTLS")?;
+    Ok(())
+}
+
+/// Send the `command` with the `tag` and read its response up to the tagged status line,
+/// returning the untagged lines before it
+fn command<S: Read + Write>(reader: &mut BufReader<S>, tag: &str, command: &str) -> Result<Vec<String>, ConnectionError> {
+    let stream = reader.get_mut();
+    stream.write_all(format!("{:} {:}\r\n", tag, command).as_bytes())?;
+    stream.flush()?;
+    let mut untagged = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        match line.strip_prefix(tag).and_then(|status| status.strip_prefix(' ')) {
+            Some(status) if status.starts_with("OK") => return Ok(untagged),
+            Some(status) => return Err(ImapError(No(status.to_string()))),
+            None => untagged.push(line),
+        }
+    }
+}
+
+/// Read a line of the server's response without its line ending
+fn read_line<S: Read>(reader: &mut BufReader<S>) -> Result<String, ConnectionError> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(String::from_utf8_lossy(&line).trim_end().to_string())
+}
+
+/// Fixtures for the tests of the protocol clients
+#[cfg(test)]
+pub(crate) mod test_support {
+    use std::io::{self, Cursor, Read, Write};
+
+    /// Connection replaying the `input` of a server and recording what the client sends
+    pub struct MockStream {
+        input: Cursor<Vec<u8>>,
+        pub output: Vec<u8>,
+    }
+
+    impl MockStream {
+        pub fn new(input: &[u8]) -> Self {
+            MockStream { input: Cursor::new(input.to_vec()), output: Vec::new() }
+        }
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+    use super::test_support::MockStream;
+
+    /// Negotiate STARTTLS with a server answering with `input`, returning the commands sent
+    fn negotiate(input: &str) -> Result<String, ConnectionError> {
+        let mut reader = BufReader::new(MockStream::new(input.as_bytes()));
+        negotiate_starttls(&mut reader)?;
+        Ok(String::from_utf8(reader.into_inner().output).unwrap())
+    }
+
+    #[test]
+    fn starttls_capability_is_looked_up_unless_in_the_greeting() {
+        let sent = negotiate("* OK [CAPABILITY IMAP4rev1 STARTTLS] ready\r\ns2 OK Begin TLS\r\n").unwrap();
+        assert_eq!(sent, "s2 STARTTLS\r\n");
+
+        let sent = negotiate("* OK ready\r\n* CAPABILITY IMAP4rev1 STARTTLS\r\ns1 OK done\r\ns2 OK Begin TLS\r\n").unwrap();
+        assert_eq!(sent, "s1 CAPABILITY\r\ns2 STARTTLS\r\n");
+    }
+
+    #[test]
+    fn starttls_is_not_sent_without_the_capability() {
+        let err = negotiate("* OK [CAPABILITY IMAP4rev1] ready\r\n").err().unwrap();
+        assert!(matches!(&err, ImapError(No(msg)) if msg.contains("does not support STARTTLS")), "unexpected error {:?}", err);
+    }
+
+    #[test]
+    fn bye_and_preauth_greetings_are_refused() {
+        let err = negotiate("* BYE Too many connections\r\n").err().unwrap();
+        assert!(matches!(&err, ImapError(No(msg)) if msg.ends_with("* BYE Too many connections")), "unexpected error {:?}", err);
+        let err = negotiate("* PREAUTH [CAPABILITY IMAP4rev1 STARTTLS] welcome\r\n").err().unwrap();
+        assert!(matches!(&err, ImapError(No(msg)) if msg.contains("skipped authentication")), "unexpected error {:?}", err);
+    }
+
+    #[test]
+    fn refused_starttls_is_an_error() {
+        let err = negotiate("* OK [CAPABILITY IMAP4rev1 STARTTLS] ready\r\ns2 BAD not now\r\n").err().unwrap();
+        assert!(matches!(&err, ImapError(No(msg)) if msg == "BAD not now"), "unexpected error {:?}", err);
+    }
+
+    #[test]
+    fn compressed_data_round_trips() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            // the greeting is uncompressed
+            stream.write_all(b"* OK ready\r\n").unwrap();
+
+            // the command may arrive in several writes
+            let (mut decompress, mut command) = (Decompress::new(false), Vec::with_capacity(1024));
+            while !command.ends_with(b"\r\n") {
+                let mut compressed = [0; 1024];
+                let read = stream.read(&mut compressed).unwrap();
+                decompress.decompress_vec(&compressed[..read], &mut command, FlushDecompress::Sync).unwrap();
+            }
+
+            let mut response = Vec::with_capacity(1024);
+            Compress::new(Compression::default(), false)
+                .compress_vec(b"* 1 EXISTS\r\na1 OK done\r\n", &mut response, FlushCompress::Sync).unwrap();
+            stream.write_all(&response).unwrap();
+            command
+        });
+
+        let compressed = Arc::new(AtomicBool::new(false));
+        let mut stream = BufReader::new(ImapStream::plain(TcpStream::connect(address).unwrap(), compressed.clone()));
+        let mut line = String::new();
+        stream.read_line(&mut line).unwrap();
+        assert_eq!(line, "* OK ready\r\n");
+
+        compressed.store(true, Ordering::SeqCst);
+        stream.get_mut().write_all(b"a1 NOOP\r\n").unwrap();
+        stream.get_mut().flush().unwrap();
+        let mut response = String::new();
+        stream.read_line(&mut response).unwrap();
+        stream.read_line(&mut response).unwrap();
+
+        assert_eq!(server.join().unwrap(), b"a1 NOOP\r\n");
+        assert_eq!(response, "* 1 EXISTS\r\na1 OK done\r\n");
+    }
+}
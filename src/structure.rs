@@ -0,0 +1,195 @@
+use std::fmt;
+
+use imap_proto::types::{BodyContentCommon, BodyContentSinglePart, BodyStructure, ContentEncoding};
+
+use crate::mime;
+use crate::render::human_size;
+
+/// One MIME part of a message, as described by its BODYSTRUCTURE
+#[derive(Debug, PartialEq)]
+pub struct Part {
+    /// IMAP part specifier to fetch the part with, e.g. `2.1`, empty for the multipart body of a message
+    pub number: String,
+    /// Nesting depth of the part in the tree
+    pub depth: usize,
+    /// MIME type, e.g. `text/plain`
+    pub content_type: String,
+    /// Size of the encoded part in bytes, unknown for multipart parts
+    pub size: Option<u32>,
+    pub filename: Option<String>,
+    /// Content-Transfer-Encoding, e.g. `base64`, unknown for multipart parts
+    pub encoding: Option<String>,
+    pub charset: Option<String>,
+}
+
+impl Part {
+    /// The fetched `data` of this part with its transfer encoding undone,
+    /// and text parts converted from their charset to UTF-8
+    pub fn decode(&self, data: &[u8]) -> Vec<u8> {
+        let encoding = match &self.encoding {
+            Some(encoding) => encoding,
+            None => return data.to_vec(),
+        };
+        // the part is fetched without its header, so one is made up from the structure for mailparse
+        let mut content_type = self.content_type.clone();
+        if let Some(charset) = &self.charset {
+            content_type = format!("{:}; charset=\"{:}\"", content_type, charset);
+        }
+        let mut message = format!("Content-Type: {:}\r\nContent-Transfer-Encoding: {:}\r\n\r\n", content_type, encoding).into_bytes();
+        message.extend_from_slice(data);
+        let parsed = match mailparse::parse_mail(&message) {
+            Ok(parsed) => parsed,
+            Err(_) => return data.to_vec(),
+        };
+        let decoded = if self.content_type.starts_with("text/") {
+            parsed.get_body().map(String::into_bytes)
+        } else {
+            parsed.get_body_raw()
+        };
+        decoded.unwrap_or_else(|_| data.to_vec())
+    }
+}
+
+impl fmt::Display for Part {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let number = if self.number.is_empty() { "-" } else { &self.number };
+        write!(f, "{:}{:}  {:}", "  ".repeat(self.depth), number, self.content_type)?;
+        if let Some(size) = self.size {
+            write!(f, "  {:}", human_size(size))?;
+        }
+        if let Some(filename) = &self.filename {
+            write!(f, "  {:}", filename)?;
+        }
+        Ok(())
+    }
+}
+
+/// The parts of the message `structure` in depth first order, numbered as in RFC 3501
+pub fn parts(structure: &BodyStructure) -> Vec<Part> {
+    let mut parts = Vec::new();
+    match structure {
+        BodyStructure::Multipart { .. } => add_parts(structure, "", 0, &mut parts),
+        // a single part message is part 1 of itself
+        _ => add_parts(structure, "1", 0, &mut parts),
+    }
+    parts
+}
+
+fn add_parts(structure: &BodyStructure, number: &str, depth: usize, parts: &mut Vec<Part>) {
+    match structure {
+        BodyStructure::Basic { common, other, .. } | BodyStructure::Text { common, other, .. } => {
+            parts.push(part(common, number.to_string(), depth, Some(other)));
+        },
+        BodyStructure::Message { common, other, body, .. } => {
+            parts.push(part(common, number.to_string(), depth, Some(other)));
+            match body.as_ref() {
+                // the parts of an attached multipart message are numbered directly below the message
+                BodyStructure::Multipart { common, bodies, .. } => {
+                    parts.push(part(common, String::new(), depth + 1, None));
+                    add_children(bodies, number, depth + 2, parts);
+                },
+                body => add_parts(body, &child_number(number, 0), depth + 1, parts),
+            }
+        },
+        BodyStructure::Multipart { common, bodies, .. } => {
+            parts.push(part(common, number.to_string(), depth, None));
+            add_children(bodies, number, depth + 1, parts);
+        },
+    }
+}
+
+fn add_children(bodies: &[BodyStructure], number: &str, depth: usize, parts: &mut Vec<Part>) {
+    for (index, body) in bodies.iter().enumerate() {
+        add_parts(body, &child_number(number, index), depth, parts);
+    }
+}
+
+/// Part specifier of the child at `index` of the part `number`
+fn child_number(number: &str, index: usize) -> String {
+    match number {
+        "" => (index + 1).to_string(),
+        parent => format!("{:}.{:}", parent, index + 1),
+    }
+}
+
+fn part(common: &BodyContentCommon, number: String, depth: usize, single: Option<&BodyContentSinglePart>) -> Part {
+    let param = |params: &Option<Vec<(&str, &str)>>, name: &str| params.iter().flatten()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| mime::decode_words(value));
+    let filename = common.disposition.as_ref().and_then(|disposition| param(&disposition.params, "filename"))
+        .or_else(|| param(&common.ty.params, "name"));
+    let encoding = single.map(|single| match single.transfer_encoding {
+        ContentEncoding::SevenBit => "7bit".to_string(),
+        ContentEncoding::EightBit => "8bit".to_string(),
+        ContentEncoding::Binary => "binary".to_string(),
+        ContentEncoding::Base64 => "base64".to_string(),
+        ContentEncoding::QuotedPrintable => "quoted-printable".to_string(),
+        ContentEncoding::Other(other) => other.to_ascii_lowercase(),
+    });
+    Part {
+        number,
+        depth,
+        content_type: format!("{:}/{:}", common.ty.ty, common.ty.subtype).to_ascii_lowercase(),
+        size: single.map(|single| single.octets),
+        filename,
+        encoding,
+        charset: param(&common.ty.params, "charset"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use imap_proto::types::{AttributeValue, Response};
+
+    use super::*;
+
+    fn structure_parts(response: &[u8]) -> Vec<(String, usize, String)> {
+        let (_, response) = imap_proto::parse_response(response).unwrap();
+        let attributes = match response {
+            Response::Fetch(_, attributes) => attributes,
+            other => panic!("Not a FETCH response: {:?}", other),
+        };
+        let structure = attributes.iter().find_map(|attribute| match attribute {
+            AttributeValue::BodyStructure(structure) => Some(structure),
+            _ => None,
+        }).unwrap();
+        parts(structure).into_iter().map(|part| (part.number, part.depth, part.content_type)).collect()
+    }
+
+    #[test]
+    fn parts_are_numbered_as_in_imap() {
+        let response = b"* 1 FETCH (BODYSTRUCTURE ((\"TEXT\" \"PLAIN\" (\"CHARSET\" \"utf-8\") NIL NIL \"7BIT\" 12 1)\
+            ((\"TEXT\" \"PLAIN\" NIL NIL NIL \"7BIT\" 5 1)(\"TEXT\" \"HTML\" NIL NIL NIL \"7BIT\" 9 1) \"ALTERNATIVE\")\
+            (\"APPLICATION\" \"PDF\" (\"NAME\" \"report.pdf\") NIL NIL \"BASE64\" 2048) \"MIXED\"))\r\n";
+        let part = |number: &str, depth, content_type: &str| (number.to_string(), depth, content_type.to_string());
+        assert_eq!(structure_parts(response), vec![
+            part("", 0, "multipart/mixed"),
+            part("1", 1, "text/plain"),
+            part("2", 1, "multipart/alternative"),
+            part("2.1", 2, "text/plain"),
+            part("2.2", 2, "text/html"),
+            part("3", 1, "application/pdf"),
+        ]);
+    }
+
+    #[test]
+    fn parts_are_decoded_by_their_encoding_and_charset() {
+        let part = |content_type: &str, encoding: &str, charset: Option<&str>| Part {
+            number: "1".to_string(),
+            depth: 0,
+            content_type: content_type.to_string(),
+            size: None,
+            filename: None,
+            encoding: Some(encoding.to_string()),
+            charset: charset.map(str::to_string),
+        };
+        assert_eq!(part("text/plain", "quoted-printable", Some("iso-8859-1")).decode(b"Gr=FC=DFe"), "Grüße".as_bytes());
+        assert_eq!(part("application/octet-stream", "base64", None).decode(b"AAEC/w=="), [0, 1, 2, 255]);
+    }
+
+    #[test]
+    fn single_part_message_is_part_one() {
+        let response = b"* 1 FETCH (BODYSTRUCTURE (\"TEXT\" \"PLAIN\" NIL NIL NIL \"7BIT\" 12 1))\r\n";
+        assert_eq!(structure_parts(response), vec![("1".to_string(), 0, "text/plain".to_string())]);
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use imap::types::Uid;
+
+use crate::message::Message;
+
+/// A message of a conversation with the replies to it
+#[derive(Debug, PartialEq)]
+pub struct Thread<T> {
+    pub message: T,
+    pub replies: Vec<Thread<T>>,
+}
+
+/// Threads of message UIDs in a raw `UID THREAD` response (RFC 5256),
+/// e.g. `* THREAD (2)(3 6 (4 23)(44 7 96))`
+pub fn parse_thread_response(response: &[u8]) -> Vec<Thread<Uid>> {
+    String::from_utf8_lossy(response).lines()
+        .filter_map(|line| line.strip_prefix("* THREAD"))
+        .flat_map(|lists| parse_lists(&mut lists.chars().peekable()))
+        .collect()
+}
+
+/// Parse the consecutive parenthesized thread lists at the start of `chars`
+fn parse_lists(chars: &mut Peekable<Chars>) -> Vec<Thread<Uid>> {
+    let mut threads = Vec::new();
+    loop {
+        match chars.peek() {
+            Some(' ') => { chars.next(); },
+            Some('(') => {
+                chars.next();
+                threads.extend(parse_list(chars));
+            },
+            _ => return threads,
+        }
+    }
+}
+
+/// Parse a thread list following its opening parenthesis, up to and including the closing one
+fn parse_list(chars: &mut Peekable<Chars>) -> Vec<Thread<Uid>> {
+    let mut members = Vec::new();
+    let mut nested = Vec::new();
+    while let Some(&next) = chars.peek() {
+        match next {
+            '(' => nested.extend(parse_lists(chars)),
+            ')' => {
+                chars.next();
+                break;
+            },
+            digit if digit.is_ascii_digit() => {
+                let mut number = String::new();
+                while let Some(&digit) = chars.peek().filter(|next| next.is_ascii_digit()) {
+                    number.push(digit);
+                    chars.next();
+                }
+                members.extend(number.parse::<Uid>().ok());
+            },
+            _ => { chars.next(); },
+        }
+    }
+    // each member replies to the one before it and the nested threads to the last one,
+    // while nested threads without any members have a missing message as their common parent
+    members.into_iter().rev().fold(nested, |replies, message| vec![Thread { message, replies }])
+}
+
+/// Threads of UIDs as threads of indexes into the `messages` with those UIDs,
+/// attaching the replies of messages that were not listed to the parent of those
+pub fn uid_threads(threads: Vec<Thread<Uid>>, messages: &[Message]) -> Vec<Thread<usize>> {
+    threads.into_iter().flat_map(|thread| {
+        let uid = thread.message;
+        let replies = uid_threads(thread.replies, messages);
+        match messages.iter().position(|message| message.uid == Some(uid)) {
+            Some(index) => vec![Thread { message: index, replies }],
+            None => replies,
+        }
+    }).collect()
+}
+
+/// Threads of indexes into the `messages` built from their Message-ID, In-Reply-To and References headers.
+/// Messages reply to their nearest listed ancestor, and start a thread of their own without one.
+pub fn build_threads(messages: &[Message]) -> Vec<Thread<usize>> {
+    let ids: HashMap<&str, usize> = messages.iter().enumerate()
+        .filter_map(|(index, message)| message.message_id.as_deref().map(|id| (id, index)))
+        .collect();
+    let mut parents: Vec<Option<usize>> = messages.iter().enumerate().map(|(index, message)| {
+        message.references.iter().rev().chain(&message.in_reply_to)
+            .filter_map(|id| ids.get(id.as_str()).copied())
+            .find(|&parent| parent != index)
+    }).collect();
+
+    // malformed or forged headers can make messages their own ancestors
+    for index in 0..parents.len() {
+        let mut ancestor = parents[index];
+        for _ in 0..parents.len() {
+            match ancestor {
+                Some(parent) if parent == index => {
+                    parents[index] = None;
+                    break;
+                },
+                Some(parent) => ancestor = parents[parent],
+                None => break,
+            }
+        }
+    }
+    replies(None, &parents)
+}
+
+/// Threads of the messages whose parent is `parent`, in their listing order
+fn replies(parent: Option<usize>, parents: &[Option<usize>]) -> Vec<Thread<usize>> {
+    parents.iter().enumerate()
+        .filter(|(_, &message_parent)| message_parent == parent)
+        .map(|(index, _)| Thread { message: index, replies: replies(Some(index), parents) })
+        .collect()
+}
+
+/// The `messages` in the order of their `threads`, each one followed by its replies
+/// with their thread depth set. Messages missing from the threads are left at the end.
+pub fn flatten(messages: Vec<Message>, threads: &[Thread<usize>]) -> Vec<Message> {
+    fn walk(threads: &[Thread<usize>], depth: usize, order: &mut Vec<(usize, usize)>) {
+        for thread in threads {
+            order.push((thread.message, depth));
+            walk(&thread.replies, depth + 1, order);
+        }
+    }
+    let mut order = Vec::new();
+    walk(threads, 0, &mut order);
+
+    let mut messages: Vec<Option<Message>> = messages.into_iter().map(Some).collect();
+    let mut threaded: Vec<Message> = order.into_iter()
+        .filter_map(|(index, depth)| messages.get_mut(index).and_then(Option::take).map(|mut message| {
+            message.thread_depth = Some(depth);
+            message
+        }))
+        .collect();
+    threaded.extend(messages.into_iter().flatten());
+    threaded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn thread<T>(message: T, replies: Vec<Thread<T>>) -> Thread<T> {
+        Thread { message, replies }
+    }
+
+    /// A message with the `id` replying to the message `parent`, with `references` for its ancestors
+    fn message(id: &str, parent: Option<&str>, references: &[&str]) -> Message {
+        let mut headers = format!("Message-ID: <{}>\r\n", id);
+        if let Some(parent) = parent {
+            headers.push_str(&format!("In-Reply-To: <{}>\r\n", parent));
+        }
+        if !references.is_empty() {
+            let references: Vec<String> = references.iter().map(|id| format!("<{}>", id)).collect();
+            headers.push_str(&format!("References: {:}\r\n", references.join(" ")));
+        }
+        headers.push_str("\r\n");
+        Message::from_rfc822(headers.as_bytes(), false).unwrap()
+    }
+
+    #[test]
+    fn thread_response_is_parsed_as_in_rfc_5256() {
+        let response = b"* THREAD (2)(3 6 (4 23)(44 7 96))\r\n";
+        assert_eq!(parse_thread_response(response), vec![
+            thread(2, vec![]),
+            thread(3, vec![thread(6, vec![
+                thread(4, vec![thread(23, vec![])]),
+                thread(44, vec![thread(7, vec![thread(96, vec![])])]),
+            ])]),
+        ]);
+    }
+
+    #[test]
+    fn threads_without_a_common_message_are_separate() {
+        // the parent of 3 and 5 is not in the mailbox
+        assert_eq!(parse_thread_response(b"* THREAD ((3)(5))\r\n"), vec![thread(3, vec![]), thread(5, vec![])]);
+    }
+
+    #[test]
+    fn replies_follow_their_nearest_listed_ancestor() {
+        let messages = vec![
+            message("a", None, &[]),
+            message("c", Some("b"), &["a", "b"]),
+            message("d", Some("a"), &["a"]),
+        ];
+        assert_eq!(build_threads(&messages), vec![thread(0, vec![thread(1, vec![]), thread(2, vec![])])]);
+    }
+
+    #[test]
+    fn reply_cycles_are_broken() {
+        let messages = vec![
+            message("a", Some("b"), &[]),
+            message("b", Some("a"), &[]),
+            message("c", Some("c"), &[]),
+        ];
+        // the first message of the cycle starts the thread
+        assert_eq!(build_threads(&messages), vec![thread(0, vec![thread(1, vec![])]), thread(2, vec![])]);
+    }
+
+    #[test]
+    fn flattened_messages_have_their_thread_depth() {
+        let messages = vec![message("a", None, &[]), message("b", Some("a"), &[]), message("c", None, &[])];
+        let threads = vec![thread(0, vec![thread(1, vec![])])];
+
+        let flattened = flatten(messages, &threads);
+        let order: Vec<(Option<&str>, Option<usize>)> = flattened.iter()
+            .map(|message| (message.message_id.as_deref(), message.thread_depth))
+            .collect();
+        // the message missing from the threads is left at the end without a depth
+        assert_eq!(order, vec![(Some("<a>"), Some(0)), (Some("<b>"), Some(1)), (Some("<c>"), None)]);
+    }
+}